@@ -1,10 +1,18 @@
-use std::collections::BTreeMap;
-use std::io::Result;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::io::{Error, Result};
+use std::iter;
 use std::net::SocketAddr;
+use std::ops::{Bound, Range};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use futures::stream::{FuturesUnordered, StreamExt, TryStreamExt};
 use madsim::net::rpc::Request;
 use madsim::net::Endpoint;
+use madsim::rand::Rng;
+use madsim::task::JoinHandle;
 
 use crate::msg::*;
 
@@ -18,95 +26,824 @@ use crate::msg::*;
 const BACKOFF_TIME: Duration = Duration::from_millis(100);
 // RETRY_TIMES is the maximum number of times a client attempts to send a request.
 const RETRY_TIMES: usize = 3;
+// ROUTE_RETRY_TIMES is the maximum number of times a client looks for the region of a key.
+const ROUTE_RETRY_TIMES: usize = 10;
+// RESOLVE_RETRY_TIMES is the maximum number of times a reader resolves the locks in its way.
+// Each time backs off first, so that the reader outlives the locks of a dead client.
+const RESOLVE_RETRY_TIMES: usize = 100;
+// LOCK_TTL is how long the locks of a transaction live after they are prewritten,
+// unless the committing client extends them.
+const LOCK_TTL: Duration = Duration::from_secs(3);
+// RESOLVED_CACHE_SIZE is the number of resolved transactions whose outcome a client remembers.
+const RESOLVED_CACHE_SIZE: usize = 1024;
 
 /// Client mainly has two purposes:
 /// One is getting a monotonically increasing timestamp from TSO (Timestamp Oracle).
-/// The other is do the transaction logic.
+/// The other is to begin transactions, which do the transaction logic.
+///
+/// A client is cheap to clone, and the clones share the connections, the
+/// routing table and the cached timestamps, so they may run transactions
+/// concurrently from different tasks.
+#[derive(Clone)]
 pub struct Client {
     ep: Endpoint,
-    tso_addr: SocketAddr,
-    txn_addr: SocketAddr,
-    start_ts: Option<u64>,
-    write_set: BTreeMap<Key, Value>,
+    tso_addrs: Vec<SocketAddr>,
+    // The last known leader of the TSO group.
+    tso_leader: Arc<Mutex<SocketAddr>>,
+    router: Arc<Router>,
+    // The number of timestamps fetched from TSO at a time.
+    ts_batch: u32,
+    // Timestamps fetched from TSO but not handed out yet.
+    ts_cache: Arc<Mutex<Range<u64>>>,
+    // Whether reads may be served by the followers of a storage group.
+    follower_read: bool,
+    // Whether transactions count as committed once all of their keys are prewritten.
+    async_commit: bool,
+    // Whether transactions on one storage node are committed in one step.
+    one_phase_commit: bool,
+    // How long the locks of a transaction live after they are prewritten.
+    lock_ttl: Duration,
+    // The tasks committing the secondaries of the transactions by start timestamp.
+    committing: Arc<Mutex<BTreeMap<u64, JoinHandle<()>>>>,
+    // The outcomes of the transactions resolved lately by start timestamp:
+    // the commit timestamp, or `None` if rolled back.
+    resolved: Arc<Mutex<BTreeMap<u64, Option<u64>>>>,
+}
+
+/// A transaction begun by `Client::begin`, reading at its start timestamp.
+///
+/// Writes are buffered until `commit`. A transaction dropped without being
+/// committed is discarded like one rolled back.
+pub struct Transaction {
+    client: Client,
+    start_ts: u64,
+    // The physical time the transaction began at, in milliseconds since the Unix epoch.
+    start_time: u64,
+    // The values to write, where `None` deletes the key.
+    write_set: BTreeMap<Key, Option<Value>>,
+    // Whether the keys have been sent to prewrite, so that they may be locked.
+    prewritten: bool,
+    // The task keeping the lock of the primary key alive while committing.
+    heartbeat: Option<JoinHandle<()>>,
+    // Whether the transaction has been committed or rolled back.
+    finished: bool,
+}
+
+/// Routes requests to the storage nodes, shared with the background tasks of
+/// a client, and used by the storage nodes to reach each other.
+pub(crate) struct Router {
+    ep: Endpoint,
+    txn_addrs: Vec<SocketAddr>,
+    // The routes of the regions by their start keys, learned from the storage nodes.
+    routes: Mutex<BTreeMap<Key, Route>>,
 }
 
 type Key = Vec<u8>;
 type Value = Vec<u8>;
 
+/// The region owning a range of keys and the storage node owning the region.
+#[derive(Debug, Clone)]
+struct Route {
+    region: Region,
+    // The leader of the storage group owning the region.
+    addr: SocketAddr,
+    // All members of the storage group.
+    peers: Arc<[SocketAddr]>,
+}
+
+/// An error telling that a request has been sent to the wrong storage node.
+pub(crate) trait RouteError {
+    /// Returns whether the region of the request has changed.
+    fn is_region_changed(&self) -> bool;
+
+    /// Returns the leader hint if the node is not the leader of its storage group.
+    fn not_leader(&self) -> Option<Option<SocketAddr>>;
+}
+
+macro_rules! impl_route_error {
+    ($($error:ident),*) => {
+        $(impl RouteError for $error {
+            fn is_region_changed(&self) -> bool {
+                matches!(self, $error::RegionChanged)
+            }
+
+            fn not_leader(&self) -> Option<Option<SocketAddr>> {
+                match self {
+                    $error::NotLeader { leader } => Some(*leader),
+                    _ => None,
+                }
+            }
+        })*
+    };
+}
+
+impl_route_error!(
+    GetError,
+    PrewriteError,
+    CommitError,
+    CheckError,
+    RollbackError,
+    ScanError,
+    HeartBeatError,
+    ResolveError
+);
+
 impl Client {
     /// Creates a new Client.
-    pub async fn new(tso_addr: SocketAddr, txn_addr: SocketAddr) -> Result<Client> {
+    ///
+    /// `tso_addrs` contains the addresses of all members of the TSO group.
+    /// `txn_addrs` contains the addresses of all storage nodes, including
+    /// every member of the storage groups. The client learns which node owns
+    /// which range of keys, and which member leads each group, from the
+    /// storage nodes.
+    pub async fn new(tso_addrs: Vec<SocketAddr>, txn_addrs: Vec<SocketAddr>) -> Result<Client> {
+        assert!(!tso_addrs.is_empty(), "no tso address");
+        assert!(!txn_addrs.is_empty(), "no txn address");
+        let ep = Endpoint::bind("0.0.0.0:0").await?;
         Ok(Client {
-            ep: Endpoint::bind("0.0.0.0:0").await?,
-            tso_addr,
-            txn_addr,
-            start_ts: None,
-            write_set: BTreeMap::new(),
+            tso_leader: Arc::new(Mutex::new(tso_addrs[0])),
+            tso_addrs,
+            router: Arc::new(Router::new(ep.clone(), txn_addrs)),
+            ep,
+            ts_batch: 1,
+            ts_cache: Arc::new(Mutex::new(0..0)),
+            follower_read: false,
+            async_commit: false,
+            one_phase_commit: false,
+            lock_ttl: LOCK_TTL,
+            committing: Arc::new(Mutex::new(BTreeMap::new())),
+            resolved: Arc::new(Mutex::new(BTreeMap::new())),
         })
     }
 
+    /// Sets the number of timestamps fetched from TSO in one request.
+    ///
+    /// The remaining timestamps are cached and handed out by later calls to
+    /// `get_timestamp`, which transactions use as start timestamps only.
+    /// Commit timestamps are always fetched from TSO after the keys are
+    /// locked, and discard the cached timestamps below them.
+    pub fn set_timestamp_batch(&mut self, count: u32) {
+        self.ts_batch = count.max(1);
+        *self.ts_cache.lock().unwrap() = 0..0;
+    }
+
+    /// Lets `get` read from any member of a storage group instead of its leader.
+    ///
+    /// A follower serves a read once it has applied every write visible at
+    /// the start timestamp of the transaction. Otherwise the read moves on to
+    /// another member and eventually to the leader.
+    pub fn set_follower_read(&mut self, enabled: bool) {
+        self.follower_read = enabled;
+    }
+
+    /// Lets `commit` return once all keys are prewritten, without committing the primary key.
+    ///
+    /// The lock of the primary key records the other keys, so that a reader
+    /// running into a lock can tell whether the transaction is committed from
+    /// the locks of all keys. The commit timestamp is the highest of the lowest
+    /// commit timestamps given by the storage nodes, which are above any
+    /// timestamp the nodes have served a read at.
+    pub fn set_async_commit(&mut self, enabled: bool) {
+        self.async_commit = enabled;
+    }
+
+    /// Lets `commit` commit a transaction in one step if all of its keys are on one storage node.
+    ///
+    /// The storage node writes the records of all keys at once without
+    /// locking them. A transaction spanning several nodes still takes two phases.
+    pub fn set_one_phase_commit(&mut self, enabled: bool) {
+        self.one_phase_commit = enabled;
+    }
+
+    /// Sets how long the locks of a transaction live after they are prewritten.
+    ///
+    /// A reader running into a lock waits for it until it expires, then rolls
+    /// the transaction back. A committing transaction keeps extending the lock
+    /// of its primary key, so the lock only expires once the client is gone.
+    pub fn set_lock_ttl(&mut self, ttl: Duration) {
+        self.lock_ttl = ttl;
+    }
+
     /// Gets a timestamp from a TSO.
     pub async fn get_timestamp(&self) -> Result<u64> {
-        let req = || TimestampRequest {};
-        let rsp = self.call_with_retry(self.tso_addr, req).await?;
+        if let Some(ts) = self.ts_cache.lock().unwrap().next() {
+            tracing::info!(ts, "get_timestamp (cached)");
+            return Ok(ts);
+        }
+        let rsp = self.call_tso(self.ts_batch).await?;
+        let end = rsp.ts + rsp.count as u64;
+        let mut cache = self.ts_cache.lock().unwrap();
+        // A concurrent call may have refilled the cache with a newer range.
+        // Keep the newer one so that cached timestamps never go backwards.
+        if rsp.ts >= cache.end {
+            *cache = rsp.ts + 1..end;
+        }
         tracing::info!(ts = rsp.ts, "get_timestamp");
         Ok(rsp.ts)
     }
 
+    /// Gets a timestamp from a TSO, above every timestamp issued before the call.
+    ///
+    /// Unlike `get_timestamp`, it never hands out a cached timestamp.
+    async fn fresh_timestamp(&self) -> Result<u64> {
+        let rsp = self.call_tso(1).await?;
+        self.skip_timestamps(rsp.ts);
+        tracing::info!(ts = rsp.ts, "fresh_timestamp");
+        Ok(rsp.ts)
+    }
+
+    /// Discards the cached timestamps not above `ts`,
+    /// so that a transaction begun later sees a commit at `ts`.
+    fn skip_timestamps(&self, ts: u64) {
+        let mut cache = self.ts_cache.lock().unwrap();
+        cache.start = cache.end.min(ts + 1).max(cache.start);
+    }
+
     /// Begins a new transaction.
-    pub async fn begin(&mut self) {
-        tracing::info!("begin");
-        assert!(self.start_ts.is_none(), "transaction already begin");
-        let start_ts = self.get_timestamp().await.unwrap();
-        self.start_ts = Some(start_ts);
-        self.write_set.clear();
-    }
-
-    /// Gets the value for a given key.
-    pub async fn get(&self, key: &[u8]) -> Result<Value> {
-        let req = || GetRequest {
-            start_ts: self.start_ts.expect("no transaction"),
+    pub async fn begin(&self) -> Result<Transaction> {
+        let start_time = physical_now();
+        let start_ts = self.get_timestamp().await?;
+        tracing::info!(start_ts, "begin");
+        Ok(Transaction {
+            client: self.clone(),
+            start_ts,
+            start_time,
+            write_set: BTreeMap::new(),
+            prewritten: false,
+            heartbeat: None,
+            finished: false,
+        })
+    }
+
+    /// Resolves the lock left on `key` by the transaction started at `lock_ts`,
+    /// committing or rolling it back as its primary key tells, together with
+    /// every other lock of the transaction in the region of `key`.
+    ///
+    /// The lock may stay if the transaction is still in progress, so the
+    /// caller should read the key again.
+    async fn resolve_lock(
+        &self,
+        key: &[u8],
+        lock_ts: u64,
+        primary: &[u8],
+        async_commit: bool,
+    ) -> Result<()> {
+        // a lock left by a transaction of this client is committed in the background
+        let task = self.committing.lock().unwrap().remove(&lock_ts);
+        if let Some(task) = task {
+            let _ = task.await;
+            return Ok(());
+        }
+        let cached = self.resolved.lock().unwrap().get(&lock_ts).copied();
+        let commit_ts = match cached {
+            Some(commit_ts) => commit_ts,
+            None => {
+                madsim::time::sleep(BACKOFF_TIME).await;
+                let status = self.settle_txn(key, lock_ts, primary, async_commit);
+                let commit_ts = match status.await? {
+                    TxnStatus::Committed(commit_ts) => Some(commit_ts),
+                    TxnStatus::RolledBack => None,
+                    _ => return Ok(()),
+                };
+                self.cache_resolved(lock_ts, commit_ts);
+                commit_ts
+            }
+        };
+        let req = |region| ResolveLockRequest {
+            region,
+            start_ts: lock_ts,
+            commit_ts,
+            keys: vec![],
+        };
+        let resolved = (self.router.call_region(key, req).await?).map_err(Error::other)?;
+        let key = String::from_utf8_lossy(key);
+        match commit_ts {
+            Some(_) => tracing::debug!(?key, lock_ts, resolved, "recovery commit"),
+            None => tracing::debug!(?key, lock_ts, resolved, "recovery rollback"),
+        }
+        Ok(())
+    }
+
+    /// Decides the outcome of the transaction started at `lock_ts`, whose lock
+    /// is left on `key`, and settles it on the primary key once the
+    /// transaction is abandoned.
+    ///
+    /// Returns `Committed` or `RolledBack` once the outcome is settled, or
+    /// another status while the transaction may be in progress.
+    async fn settle_txn(
+        &self,
+        key: &[u8],
+        lock_ts: u64,
+        primary: &[u8],
+        async_commit: bool,
+    ) -> Result<TxnStatus> {
+        // the primary key of an async-commit transaction may not be prewritten yet
+        let status = self
+            .check_txn_status(primary, lock_ts, !async_commit)
+            .await?;
+        match status {
+            TxnStatus::Locked { ttl } => {
+                tracing::debug!(lock_ts, ttl, "transaction alive");
+                Ok(status)
+            }
+            TxnStatus::Uncommitted => {
+                // which it can no longer be once the lock found has expired
+                let status = self.check_txn_status(key, lock_ts, false).await?;
+                if !matches!(status, TxnStatus::AsyncCommit { .. }) {
+                    return Ok(TxnStatus::Uncommitted);
+                }
+                self.check_txn_status(primary, lock_ts, true).await
+            }
+            TxnStatus::AsyncCommit {
+                min_commit_ts,
+                secondaries,
+            } => {
+                let status = self.check_secondaries(lock_ts, min_commit_ts, &secondaries);
+                match status.await? {
+                    TxnStatus::Committed(commit_ts) => {
+                        // settle the outcome on the primary key first
+                        let req = |region| CommitRequest {
+                            region,
+                            is_primary: true,
+                            key: primary.into(),
+                            start_ts: lock_ts,
+                            commit_ts,
+                        };
+                        (self.router.call_region(primary, req).await?).map_err(Error::other)?;
+                        Ok(TxnStatus::Committed(commit_ts))
+                    }
+                    TxnStatus::RolledBack => {
+                        // the transaction can no longer commit
+                        let keys = iter::once(primary.to_vec())
+                            .chain(secondaries)
+                            .collect::<Vec<_>>();
+                        match self.rollback_keys(lock_ts, &keys).await? {
+                            Some(commit_ts) => Ok(TxnStatus::Committed(commit_ts)),
+                            None => Ok(TxnStatus::RolledBack),
+                        }
+                    }
+                    status => {
+                        tracing::debug!(lock_ts, "async commit in progress");
+                        Ok(status)
+                    }
+                }
+            }
+            status => Ok(status),
+        }
+    }
+
+    /// Remembers the outcome of the transaction started at `start_ts`: its
+    /// commit timestamp, or `None` if it is rolled back.
+    fn cache_resolved(&self, start_ts: u64, commit_ts: Option<u64>) {
+        let mut resolved = self.resolved.lock().unwrap();
+        resolved.insert(start_ts, commit_ts);
+        // the locks of the oldest transactions are the least likely to be found again
+        if resolved.len() > RESOLVED_CACHE_SIZE {
+            resolved.pop_first();
+        }
+    }
+
+    /// Checks the status of the transaction started at `start_ts` on its
+    /// primary key, rolling it back if its lock has expired, or if there is
+    /// no lock and `rollback_if_not_exist` is set.
+    async fn check_txn_status(
+        &self,
+        primary: &[u8],
+        start_ts: u64,
+        rollback_if_not_exist: bool,
+    ) -> Result<TxnStatus> {
+        let req = |region| CheckTxnStatusRequest {
+            region,
+            primary_key: primary.into(),
+            lock_ts: start_ts,
+            current_time: physical_now(),
+            rollback_if_not_exist,
+        };
+        (self.router.call_region(primary, req).await?).map_err(Error::other)
+    }
+
+    /// Keeps extending the lock of `primary`, the primary key of the
+    /// transaction started at `start_ts` at physical time `start_time`, in the
+    /// background until the lock is gone.
+    fn keep_alive(&self, primary: Key, start_ts: u64, start_time: u64) -> JoinHandle<()> {
+        let client = self.clone();
+        madsim::task::spawn(async move {
+            loop {
+                madsim::time::sleep(client.lock_ttl / 2).await;
+                let req = |region| TxnHeartBeatRequest {
+                    region,
+                    primary_key: primary.clone(),
+                    start_ts,
+                    advise_ttl: client.lock_ttl(start_time),
+                };
+                match client.router.call_region(&primary, req).await {
+                    Ok(Ok(ttl)) => tracing::debug!(start_ts, ttl, "heartbeat"),
+                    // the transaction has been committed or rolled back
+                    Ok(Err(HeartBeatError::LockNotFound)) => return,
+                    Ok(Err(e)) => tracing::debug!(?e, "heartbeat"),
+                    Err(e) => tracing::debug!(?e, "heartbeat"),
+                }
+            }
+        })
+    }
+
+    /// Returns the TTL of a lock prewritten now by a transaction started at
+    /// physical time `start_time`.
+    fn lock_ttl(&self, start_time: u64) -> u64 {
+        physical_now().saturating_sub(start_time) + self.lock_ttl.as_millis() as u64
+    }
+
+    /// Rolls back `keys` of the transaction started at `start_ts`, the first
+    /// of which is the primary key.
+    ///
+    /// Returns the commit timestamp instead if the primary key is already committed.
+    async fn rollback_keys(&self, start_ts: u64, keys: &[Key]) -> Result<Option<u64>> {
+        let Some((primary, secondaries)) = keys.split_first() else {
+            return Ok(None);
+        };
+        // the transaction can no longer commit once its primary key is rolled back
+        match self
+            .router
+            .call_batch([primary], rollback_request(start_ts))
+            .await?
+        {
+            Ok(_) => {}
+            Err(RollbackError::AlreadyCommitted { commit_ts }) => return Ok(Some(commit_ts)),
+            Err(e) => return Err(Error::other(e)),
+        }
+        let req = rollback_request(start_ts);
+        (self.router.call_batch(secondaries, req).await?).map_err(Error::other)?;
+        Ok(None)
+    }
+
+    /// Commits `primary` if any, then `secondaries`, in the background.
+    ///
+    /// A transaction of the client running into their locks waits for them.
+    fn commit_in_background(
+        &self,
+        primary: Option<Key>,
+        secondaries: Vec<Key>,
+        start_ts: u64,
+        commit_ts: u64,
+    ) {
+        if primary.is_none() && secondaries.is_empty() {
+            return;
+        }
+        let router = self.router.clone();
+        let task = madsim::task::spawn(async move {
+            let commit = async {
+                if let Some(primary) = &primary {
+                    let req = commit_request(start_ts, commit_ts, true);
+                    (router.call_batch([primary], req).await?).map_err(Error::other)?;
+                }
+                let req = commit_request(start_ts, commit_ts, false);
+                (router.call_batch(&secondaries, req).await?).map_err(Error::other)
+            };
+            if let Err(e) = commit.await {
+                tracing::debug!(?e, "commit in background");
+            }
+        });
+        let mut committing = self.committing.lock().unwrap();
+        committing.retain(|_, task| !task.is_finished());
+        committing.insert(start_ts, task);
+    }
+
+    /// Rebuilds the outcome of an async-commit transaction from the locks of
+    /// its secondaries, once the lock of its primary key has expired.
+    ///
+    /// Returns `Committed` if every secondary is locked or committed, and
+    /// `RolledBack` if a secondary is rolled back. A secondary found neither
+    /// way is rolled back unless its prewrite gets there first, in which case
+    /// the transaction may still be prewriting and `Uncommitted` is returned.
+    async fn check_secondaries(
+        &self,
+        start_ts: u64,
+        min_commit_ts: u64,
+        secondaries: &[Key],
+    ) -> Result<TxnStatus> {
+        let req = |region, keys: &[&Key]| CheckLocksRequest {
+            region,
+            keys: keys.iter().map(|&key| key.clone()).collect(),
+            start_ts,
+        };
+        let locks = (self.router.call_batch(secondaries, req).await?).map_err(Error::other)?;
+        let mut commit_ts = min_commit_ts;
+        let mut missing = None;
+        for (key, status) in locks {
+            match status {
+                LockStatus::Committed { commit_ts } => return Ok(TxnStatus::Committed(commit_ts)),
+                LockStatus::Locked { min_commit_ts } => commit_ts = commit_ts.max(min_commit_ts),
+                LockStatus::Missing => missing = Some(key),
+            }
+        }
+        let Some(key) = missing else {
+            return Ok(TxnStatus::Committed(commit_ts));
+        };
+        // a secondary is never prewritten once rolled back
+        match self.check_txn_status(key, start_ts, true).await? {
+            status @ (TxnStatus::Committed(_) | TxnStatus::RolledBack) => Ok(status),
+            _ => Ok(TxnStatus::Uncommitted),
+        }
+    }
+
+    /// Splits the region containing `split_key` at the key.
+    ///
+    /// The keys from `split_key` on form a new region, which moves to the
+    /// storage node at `target`, or stays on the same node if there is none.
+    pub async fn split_region(&self, split_key: &[u8], target: Option<SocketAddr>) -> Result<()> {
+        tracing::info!(split_key = ?String::from_utf8_lossy(split_key), ?target, "split region");
+        let new_region_id = madsim::rand::thread_rng().gen();
+        self.call_admin(|| async {
+            let route = self.router.route(split_key).await?;
+            let req = || SplitRequest {
+                region: route.region.epoch(),
+                split_key: split_key.into(),
+                new_region_id,
+                target,
+            };
+            self.router.call_with_retry(route.addr, req).await
+        })
+        .await
+    }
+
+    /// Merges the region containing `key` into the region right before it,
+    /// on the storage node owning that region.
+    pub async fn merge_region(&self, key: &[u8]) -> Result<()> {
+        tracing::info!(key = ?String::from_utf8_lossy(key), "merge region");
+        self.call_admin(|| async {
+            let route = self.router.route(key).await?;
+            let prev = {
+                let routes = self.router.routes.lock().unwrap();
+                let range = ..route.region.start_key.clone();
+                routes
+                    .range(range)
+                    .next_back()
+                    .map(|(_, prev)| prev.clone())
+            };
+            let prev = prev
+                .filter(|prev| prev.region.end_key == route.region.start_key)
+                .ok_or_else(|| Error::other("no region right before"))?;
+            let req = || MergeRequest {
+                region: route.region.epoch(),
+                into: prev.region.epoch(),
+                target: prev.addr,
+            };
+            self.router.call_with_retry(route.addr, req).await
+        })
+        .await
+    }
+
+    /// Sends an administrative request built by `call` with fresh routes,
+    /// until the regions it is built for do not change in the meantime.
+    async fn call_admin<F, Fut>(&self, mut call: F) -> Result<()>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<std::result::Result<(), RegionError>>>,
+    {
+        for _ in 0..RETRY_TIMES {
+            self.router.refresh_routes().await;
+            match call().await? {
+                Err(RegionError::RegionChanged | RegionError::NotLeader { .. }) => {
+                    madsim::time::sleep(BACKOFF_TIME).await
+                }
+                rsp => return rsp.map_err(Error::other),
+            }
+        }
+        Err(Error::other(RegionError::RegionChanged))
+    }
+
+    /// Requests timestamps from the TSO leader, following the redirects of the TSO group.
+    async fn call_tso(&self, count: u32) -> Result<TimestampResponse> {
+        let mut addr = *self.tso_leader.lock().unwrap();
+        let mut timeout = BACKOFF_TIME;
+        let mut last_err = None;
+        for i in 1..=RETRY_TIMES * self.tso_addrs.len() {
+            let req = TimestampRequest { count };
+            match self.ep.call_timeout(addr, req, timeout).await {
+                Ok(Ok(rsp)) => {
+                    *self.tso_leader.lock().unwrap() = addr;
+                    return Ok(rsp);
+                }
+                Ok(Err(e)) => {
+                    let TsoError::NotLeader { leader } = e;
+                    match leader {
+                        Some(leader) => addr = leader,
+                        None => {
+                            // the group may be electing a leader
+                            madsim::time::sleep(BACKOFF_TIME).await;
+                            addr = self.next_tso(addr);
+                        }
+                    }
+                    last_err = Some(Error::other(e));
+                }
+                Err(e) => {
+                    last_err = Some(e);
+                    addr = self.next_tso(addr);
+                }
+            }
+            // back off after trying every member once
+            if i % self.tso_addrs.len() == 0 {
+                timeout *= 2;
+            }
+        }
+        Err(last_err.unwrap())
+    }
+
+    fn next_tso(&self, addr: SocketAddr) -> SocketAddr {
+        let i = self.tso_addrs.iter().position(|a| *a == addr).unwrap_or(0);
+        self.tso_addrs[(i + 1) % self.tso_addrs.len()]
+    }
+}
+
+impl Transaction {
+    /// Returns the start timestamp of the transaction.
+    pub fn start_ts(&self) -> u64 {
+        self.start_ts
+    }
+
+    /// Gets the value for a given key, or `None` if the key does not exist.
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Value>> {
+        let req = |region| GetRequest {
+            region,
+            start_ts: self.start_ts,
             key: key.into(),
+            follower_read: self.client.follower_read,
         };
-        loop {
-            let (lock_ts, primary) = match self.call_with_retry(self.txn_addr, req).await? {
+        for _ in 0..RESOLVE_RETRY_TIMES {
+            let rsp = match self.client.follower_read {
+                true => self.client.router.call_follower(key, req).await?,
+                false => None,
+            };
+            let rsp = match rsp {
+                Some(rsp) => rsp,
+                None => self.client.router.call_region(key, req).await?,
+            };
+            let (lock_ts, primary, async_commit) = match rsp {
                 Ok(value) => {
-                    let value = value.unwrap_or_default();
                     tracing::info!(
                         key = ?String::from_utf8_lossy(key),
-                        value = ?String::from_utf8_lossy(&value),
+                        value = ?value.as_deref().map(String::from_utf8_lossy),
                         "get"
                     );
                     return Ok(value);
                 }
-                Err(GetError::IsLocked { ts, primary }) => (ts, primary),
+                Err(GetError::IsLocked {
+                    ts,
+                    primary,
+                    async_commit,
+                }) => (ts, primary, async_commit),
+                Err(
+                    e @ (GetError::RegionChanged
+                    | GetError::NotLeader { .. }
+                    | GetError::DataNotReady { .. }),
+                ) => return Err(Error::other(e)),
             };
-            madsim::time::sleep(BACKOFF_TIME).await;
-            let req = || CheckRequest {
-                key: primary.clone(),
-                lock_ts,
+            self.client
+                .resolve_lock(key, lock_ts, &primary, async_commit)
+                .await?;
+        }
+        Err(Error::other("key is locked"))
+    }
+
+    /// Gets the values for the given keys in one batch per region,
+    /// returning `None` for a key that does not exist.
+    pub async fn batch_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<Value>>> {
+        let start_ts = self.start_ts;
+        let req = |region, keys: &[&Key]| BatchGetRequest {
+            region,
+            start_ts,
+            keys: keys.iter().map(|&key| key.clone()).collect(),
+        };
+        let mut pending = keys
+            .iter()
+            .map(|&key| key.to_vec())
+            .collect::<BTreeSet<_>>();
+        let mut values = BTreeMap::<Key, Option<Value>>::new();
+        for _ in 0..RESOLVE_RETRY_TIMES {
+            if pending.is_empty() {
+                tracing::info!(count = keys.len(), "batch get");
+                return Ok(keys.iter().map(|&key| values[key].clone()).collect());
+            }
+            let mut locked = vec![];
+            let output = |key: &Key, result| {
+                match result {
+                    Ok(value) => {
+                        values.insert(key.clone(), value);
+                    }
+                    Err(GetError::IsLocked {
+                        ts,
+                        primary,
+                        async_commit,
+                    }) => locked.push((key.clone(), ts, primary, async_commit)),
+                    Err(e) => return Err(e),
+                }
+                Ok(())
             };
-            match self.call_with_retry(self.txn_addr, req).await? {
-                Some(commit_ts) => {
-                    tracing::debug!(key = ?String::from_utf8_lossy(key), lock_ts, "recovery commit");
-                    let req = || CommitRequest {
-                        is_primary: key == primary,
-                        key: key.into(),
-                        start_ts: lock_ts,
-                        commit_ts,
-                    };
-                    self.call_with_retry(self.txn_addr, req).await?.unwrap();
+            (self
+                .client
+                .router
+                .call_batch_each(&pending, req, output)
+                .await?)
+                .map_err(Error::other)?;
+            // only the locked keys are read again
+            let resolve = (locked.iter())
+                .map(|(key, ts, primary, async_commit)| {
+                    self.client.resolve_lock(key, *ts, primary, *async_commit)
+                })
+                .collect::<FuturesUnordered<_>>();
+            resolve.try_collect::<()>().await?;
+            pending = locked.into_iter().map(|(key, ..)| key).collect();
+        }
+        Err(Error::other("keys are locked"))
+    }
+
+    /// Scans the keys in `range` in ascending order, returning at most `limit`
+    /// of them with their values. An empty end key means unbounded.
+    pub async fn scan(&self, range: Range<&[u8]>, limit: usize) -> Result<Vec<(Key, Value)>> {
+        self.scan_range(range, limit, false).await
+    }
+
+    /// Scans the keys in `range` in descending order, returning at most `limit`
+    /// of them with their values. An empty end key means unbounded.
+    pub async fn scan_reverse(
+        &self,
+        range: Range<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Key, Value)>> {
+        self.scan_range(range, limit, true).await
+    }
+
+    // Scans the regions overlapping `range` one by one, from the end of
+    // `range` on if `reverse` is set.
+    async fn scan_range(
+        &self,
+        range: Range<&[u8]>,
+        limit: usize,
+        reverse: bool,
+    ) -> Result<Vec<(Key, Value)>> {
+        let start_ts = self.start_ts;
+        let mut pairs = vec![];
+        // the part of the range left to scan
+        let (mut start, mut end) = (range.start.to_vec(), range.end.to_vec());
+        let mut retries = 0;
+        let mut resolved = 0;
+        while pairs.len() < limit && (end.is_empty() || start < end) {
+            let route = match reverse {
+                true => self.client.router.route_before(&end).await?,
+                false => self.client.router.route(&start).await?,
+            };
+            let region = &route.region;
+            let start_key = start.clone().max(region.start_key.clone());
+            let end_key = match (end.is_empty(), region.end_key.is_empty()) {
+                (true, _) => region.end_key.clone(),
+                (false, true) => end.clone(),
+                (false, false) => end.clone().min(region.end_key.clone()),
+            };
+            let req = |region| ScanRequest {
+                region,
+                start_ts,
+                start_key: start_key.clone(),
+                end_key: end_key.clone(),
+                limit: limit - pairs.len(),
+                reverse,
+            };
+            match self.client.router.call_route(&route, req).await? {
+                Some(Ok(found)) => pairs.extend(found),
+                Some(Err(ScanError::IsLocked {
+                    key,
+                    ts,
+                    primary,
+                    async_commit,
+                })) if resolved < RESOLVE_RETRY_TIMES => {
+                    resolved += 1;
+                    self.client
+                        .resolve_lock(&key, ts, &primary, async_commit)
+                        .await?;
+                    continue;
                 }
-                None => {
-                    tracing::debug!(key = ?String::from_utf8_lossy(key), lock_ts, "recovery rollback");
-                    let req = || RollbackRequest {
-                        key: key.into(),
-                        start_ts: lock_ts,
-                    };
-                    self.call_with_retry(self.txn_addr, req).await?.unwrap();
+                Some(Err(e)) => return Err(Error::other(e)),
+                None if retries < ROUTE_RETRY_TIMES => {
+                    retries += 1;
+                    continue;
                 }
+                None => return Err(Error::other("region keeps changing")),
+            }
+            retries = 0;
+            match reverse {
+                true if start_key == start => break,
+                true => end = start_key,
+                false if end_key == end => break,
+                false => start = end_key,
             }
         }
+        tracing::info!(
+            start = ?String::from_utf8_lossy(range.start),
+            end = ?String::from_utf8_lossy(range.end),
+            reverse,
+            count = pairs.len(),
+            "scan"
+        );
+        Ok(pairs)
     }
 
     /// Sets keys in a buffer until commit time.
@@ -116,56 +853,524 @@ impl Client {
             value = ?String::from_utf8_lossy(value),
             "set"
         );
-        self.write_set.insert(key.into(), value.into());
+        self.write_set.insert(key.into(), Some(value.into()));
+    }
+
+    /// Deletes a key at commit time.
+    pub async fn delete(&mut self, key: &[u8]) {
+        tracing::info!(key = ?String::from_utf8_lossy(key), "delete");
+        self.write_set.insert(key.into(), None);
     }
 
-    /// Commits a transaction.
-    pub async fn commit(&self) -> Result<bool> {
-        tracing::info!("commit");
+    /// Commits the transaction.
+    ///
+    /// Returns `false` if the transaction is aborted by a conflict or rolled
+    /// back by a reader, and an error carrying the `CommitError` if the lock
+    /// of the primary key is gone or taken by another transaction.
+    ///
+    /// The locks left by a failed commit are rolled back before returning.
+    pub async fn commit(mut self) -> Result<bool> {
+        tracing::info!(start_ts = self.start_ts, "commit");
+        let committed = self.try_commit().await;
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.abort();
+        }
+        self.finished = true;
+        if matches!(committed, Ok(true)) || !self.prewritten {
+            return committed;
+        }
+        match self.rollback_prewritten().await {
+            Ok(None) => committed,
+            // the commit of the primary key got through after all, but its
+            // response was lost, so finish it and still report the error
+            Ok(Some(commit_ts)) => {
+                let secondaries = self.write_set.keys().skip(1).cloned().collect();
+                (self.client).commit_in_background(None, secondaries, self.start_ts, commit_ts);
+                committed
+            }
+            Err(e) => {
+                tracing::debug!(?e, "rollback after a failed commit");
+                committed
+            }
+        }
+    }
+
+    /// Rolls back the transaction, discarding its buffered writes and
+    /// removing the locks of any key already prewritten.
+    pub async fn rollback(mut self) -> Result<()> {
+        tracing::info!(start_ts = self.start_ts, "rollback");
+        self.finished = true;
+        match self.rollback_prewritten().await? {
+            None => Ok(()),
+            Some(commit_ts) => Err(Error::other(RollbackError::AlreadyCommitted { commit_ts })),
+        }
+    }
+
+    /// Rolls back the keys sent to prewrite, if any.
+    ///
+    /// Returns the commit timestamp instead if the primary key is already committed.
+    async fn rollback_prewritten(&self) -> Result<Option<u64>> {
+        if !self.prewritten {
+            return Ok(None);
+        }
+        let keys = self.write_set.keys().cloned().collect::<Vec<_>>();
+        self.client.rollback_keys(self.start_ts, &keys).await
+    }
+
+    async fn try_commit(&mut self) -> Result<bool> {
         if self.write_set.is_empty() {
             // read-only transaction
             return Ok(true);
         }
-        let start_ts = self.start_ts.expect("no transaction");
+        let start_ts = self.start_ts;
 
-        // Get commit timestamp
-        let req = || TimestampRequest {};
-        let rsp = self.call_with_retry(self.tso_addr, req).await?;
-        let commit_ts = rsp.ts;
+        if self.client.one_phase_commit {
+            if let Some(committed) = self.commit_one_phase(start_ts).await? {
+                return Ok(committed);
+            }
+        }
 
         // PreWrite phase
         // first key is primary
+        // the keys of every region are prewritten in one batch, all batches at once,
+        // and the rest are cancelled once one fails
+        // a batch in flight may lock its keys even if the others fail
+        // an async-commit transaction commits above every timestamp issued so far
+        let floor_ts = match self.client.async_commit {
+            true => self.client.fresh_timestamp().await?,
+            false => 0,
+        };
+        self.prewritten = true;
         let primary_key = self.write_set.keys().next().unwrap();
-        for (key, value) in &self.write_set {
-            let req = || PrewriteRequest {
-                start_ts,
-                key: key.clone(),
-                value: value.clone(),
-                primary_key: primary_key.clone(),
-            };
-            let rsp = self.call_with_retry(self.txn_addr, req).await?;
-            if rsp.is_err() {
-                return Ok(false);
+        let lock_ttl = self.client.lock_ttl(self.start_time);
+        let secondaries = self.write_set.keys().skip(1).cloned().collect::<Vec<_>>();
+        let req = |region, keys: &[&Key]| BatchPrewriteRequest {
+            region,
+            start_ts,
+            mutations: (keys.iter())
+                .map(|&key| Mutation {
+                    key: key.clone(),
+                    value: self.write_set[key].clone(),
+                })
+                .collect(),
+            primary_key: primary_key.clone(),
+            async_commit: self.client.async_commit,
+            secondaries: match self.client.async_commit && keys.contains(&primary_key) {
+                true => secondaries.clone(),
+                false => vec![],
+            },
+            min_commit_ts: floor_ts,
+            lock_ttl,
+            start_time: self.start_time,
+        };
+        // the primary lock is kept alive from the moment it is written
+        let mut min_commit_ts = 0;
+        let output = |key: &Key, result: std::result::Result<u64, PrewriteError>| {
+            min_commit_ts = min_commit_ts.max(result?);
+            if key == primary_key {
+                let heartbeat = (self.client).keep_alive(key.clone(), start_ts, self.start_time);
+                self.heartbeat = Some(heartbeat);
             }
+            Ok(())
+        };
+        let result = (self.client.router)
+            .call_batch_each(self.write_set.keys(), req, output)
+            .await?;
+        if result.is_err() {
+            return Ok(false);
+        }
+
+        if self.client.async_commit {
+            // the transaction is committed once all keys are locked,
+            // possibly above every timestamp issued so far
+            self.client.skip_timestamps(min_commit_ts);
+            let primary_key = Some(primary_key.clone());
+            self.client
+                .commit_in_background(primary_key, secondaries, start_ts, min_commit_ts);
+            return Ok(true);
         }
 
+        // Get commit timestamp
+        // only after all locks are in place, so that a reader starting later
+        // finds either the locks or the commit
+        // a cached timestamp may have been issued before a reader's start timestamp
+        let commit_ts = self.client.fresh_timestamp().await?;
+
         // Commit phase
-        let mut committed = false;
-        for key in self.write_set.keys() {
-            let req = || CommitRequest {
+        // the transaction is committed once the primary is
+        let req = commit_request(start_ts, commit_ts, true);
+        match self.client.router.call_batch([primary_key], req).await? {
+            Ok(_) => {}
+            // a reader has rolled the transaction back
+            Err(CommitError::AlreadyRolledBack) => return Ok(false),
+            Err(e) => return Err(Error::other(e)),
+        }
+
+        // the secondaries are committed in the background,
+        // and a reader running into their locks resolves them from the primary
+        self.client
+            .commit_in_background(None, secondaries, start_ts, commit_ts);
+        Ok(true)
+    }
+
+    /// Commits the transaction in one step if all of its keys are on one storage node.
+    ///
+    /// Returns `None` if the keys span several nodes.
+    async fn commit_one_phase(&self, start_ts: u64) -> Result<Option<bool>> {
+        // a request whose response is lost may have been applied, so give up
+        // on two phases once one is sent, and send it again as it is
+        let mut sent = None;
+        for _ in 0..ROUTE_RETRY_TIMES {
+            let mut regions = BTreeMap::<_, (Route, Vec<_>)>::new();
+            for (key, value) in &self.write_set {
+                let route = self.client.router.route(key).await?;
+                let region = regions.entry(route.region.id).or_insert((route, vec![]));
+                region.1.push(Mutation {
+                    key: key.clone(),
+                    value: value.clone(),
+                });
+            }
+            let route = regions.values().next().unwrap().0.clone();
+            if regions.values().any(|(r, _)| r.peers != route.peers) {
+                return match sent {
+                    Some(_) => Err(Error::other("one-phase commit interrupted")),
+                    None => Ok(None),
+                };
+            }
+            let regions = (regions.into_values())
+                .map(|(route, mutations)| (route.region.epoch(), mutations))
+                .collect::<Vec<_>>();
+            // the commit timestamp is not below a fresh timestamp, like in two phases
+            let min_commit_ts = match sent {
+                Some(ts) => ts,
+                None => *sent.insert(self.client.fresh_timestamp().await?),
+            };
+            let req = |_| OnePhaseCommitRequest {
                 start_ts,
-                commit_ts,
-                key: key.clone(),
-                is_primary: key == primary_key,
+                min_commit_ts,
+                regions: regions.clone(),
             };
-            match self.call_with_retry(self.txn_addr, req).await {
-                Ok(Ok(())) => committed = true,
-                Err(e) if !committed => return Err(e),
-                Err(_) | Ok(Err(_)) => return Ok(true),
+            match self.client.router.call_route(&route, req).await? {
+                Some(Ok(commit_ts)) => {
+                    tracing::debug!(commit_ts, "one-phase commit");
+                    // the node may commit above every timestamp issued so far
+                    self.client.skip_timestamps(commit_ts);
+                    return Ok(Some(true));
+                }
+                Some(Err(_)) => return Ok(Some(false)),
+                None => {}
             }
         }
+        Err(Error::other("region keeps changing"))
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.abort();
+        }
+        if self.finished {
+            return;
+        }
+        tracing::info!(start_ts = self.start_ts, "transaction dropped");
+        // a commit has been cancelled, so roll back its locks in the background
+        if self.prewritten {
+            let client = self.client.clone();
+            let start_ts = self.start_ts;
+            let keys = self.write_set.keys().cloned().collect::<Vec<_>>();
+            madsim::task::spawn(async move {
+                if let Err(e) = client.rollback_keys(start_ts, &keys).await {
+                    tracing::debug!(?e, "rollback in background");
+                }
+            });
+        }
+    }
+}
+
+impl Router {
+    /// Creates a router to the storage nodes in `txn_addrs`, calling them through `ep`.
+    pub(crate) fn new(ep: Endpoint, txn_addrs: Vec<SocketAddr>) -> Self {
+        Router {
+            ep,
+            txn_addrs,
+            routes: Mutex::new(BTreeMap::new()),
+        }
+    }
 
-        Ok(true)
+    /// Sends a request about `key` to the leader of the storage group owning it.
+    ///
+    /// The request is built for the region of `key`. Whenever the region has
+    /// changed, the routing table is refreshed and the request is sent again.
+    /// Whenever the leader has changed, the request is sent to the new leader.
+    pub(crate) async fn call_region<F, R, T, E>(
+        &self,
+        key: &[u8],
+        mut request: F,
+    ) -> Result<R::Response>
+    where
+        F: FnMut(RegionEpoch) -> R,
+        R: Request<Response = std::result::Result<T, E>>,
+        E: RouteError,
+    {
+        for _ in 0..ROUTE_RETRY_TIMES {
+            let route = self.route(key).await?;
+            if let Some(rsp) = self.call_route(&route, &mut request).await? {
+                return Ok(rsp);
+            }
+        }
+        Err(Error::other("region keeps changing"))
+    }
+
+    /// Sends requests about `keys` in batches, one to the leader of each region.
+    ///
+    /// A batch is built for a region and the keys in it. Whenever a batch is
+    /// rejected because the region or the leader has changed, its keys are
+    /// grouped again with the new routes. Returns the output of every key in
+    /// no particular order, or the error of the first key that fails, dropping
+    /// the batches still in flight.
+    async fn call_batch<'a, F, R, T, E>(
+        &self,
+        keys: impl IntoIterator<Item = &'a Key>,
+        request: F,
+    ) -> Result<std::result::Result<Vec<(&'a Key, T)>, E>>
+    where
+        F: Fn(RegionEpoch, &[&'a Key]) -> R,
+        R: Request<Response = std::result::Result<Vec<std::result::Result<T, E>>, E>>,
+        E: RouteError,
+    {
+        let mut done = vec![];
+        let output = |key, result: std::result::Result<T, E>| {
+            done.push((key, result?));
+            Ok(())
+        };
+        let result = self.call_batch_each(keys, request, output).await?;
+        Ok(result.map(|()| done))
+    }
+
+    /// Sends requests about `keys` in batches like `call_batch`, and hands the
+    /// result of every key to `output` as it arrives.
+    ///
+    /// Stops at the first error of a batch, or returned by `output`.
+    async fn call_batch_each<'a, F, R, T, E>(
+        &self,
+        keys: impl IntoIterator<Item = &'a Key>,
+        request: F,
+        mut output: impl FnMut(&'a Key, std::result::Result<T, E>) -> std::result::Result<(), E>,
+    ) -> Result<std::result::Result<(), E>>
+    where
+        F: Fn(RegionEpoch, &[&'a Key]) -> R,
+        R: Request<Response = std::result::Result<Vec<std::result::Result<T, E>>, E>>,
+        E: RouteError,
+    {
+        let request = &request;
+        let mut pending = keys.into_iter().collect::<Vec<_>>();
+        for _ in 0..ROUTE_RETRY_TIMES {
+            if pending.is_empty() {
+                return Ok(Ok(()));
+            }
+            let mut batches = BTreeMap::<_, (Route, Vec<_>)>::new();
+            for key in pending.drain(..) {
+                let route = self.route(key).await?;
+                let batch = batches.entry(route.region.id).or_insert((route, vec![]));
+                batch.1.push(key);
+            }
+            let mut calls = (batches.into_values())
+                .map(|(route, keys)| async move {
+                    let rsp = self
+                        .call_route(&route, |region| request(region, &keys))
+                        .await;
+                    (keys, rsp)
+                })
+                .collect::<FuturesUnordered<_>>();
+            while let Some((keys, rsp)) = calls.next().await {
+                match rsp? {
+                    Some(Ok(results)) => {
+                        for (key, result) in keys.into_iter().zip(results) {
+                            if let Err(e) = output(key, result) {
+                                return Ok(Err(e));
+                            }
+                        }
+                    }
+                    Some(Err(e)) => return Ok(Err(e)),
+                    None => pending.extend(keys),
+                }
+            }
+        }
+        Err(Error::other("region keeps changing"))
+    }
+
+    /// Sends a request built for the region of `route` to the leader of its storage group.
+    ///
+    /// Returns `None` if the request has to be routed again, after refreshing
+    /// the routing table if the region has changed, or moving the route to
+    /// the new leader if the leader has changed.
+    async fn call_route<F, R, T, E>(
+        &self,
+        route: &Route,
+        mut request: F,
+    ) -> Result<Option<R::Response>>
+    where
+        F: FnMut(RegionEpoch) -> R,
+        R: Request<Response = std::result::Result<T, E>>,
+        E: RouteError,
+    {
+        let rsp = match (self.call_with_retry(route.addr, || request(route.region.epoch()))).await {
+            Ok(rsp) => rsp,
+            // the leader may have failed
+            Err(e) if route.peers.len() > 1 => {
+                tracing::debug!(addr = %route.addr, ?e, "storage node unreachable");
+                self.switch_leader(route, None);
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        match &rsp {
+            Err(e) if e.is_region_changed() => {
+                tracing::debug!(region = route.region.id, "region changed");
+                self.refresh_routes().await;
+            }
+            Err(e) if e.not_leader().is_some() => {
+                let leader = e.not_leader().unwrap();
+                tracing::debug!(addr = %route.addr, ?leader, "not leader");
+                if leader.is_none() {
+                    // the group may be electing a leader
+                    madsim::time::sleep(BACKOFF_TIME).await;
+                }
+                self.switch_leader(route, leader);
+            }
+            _ => return Ok(Some(rsp)),
+        }
+        Ok(None)
+    }
+
+    /// Sends a read to the members of the storage group owning `key` in turn,
+    /// starting from a random one to spread the load.
+    ///
+    /// Returns `None` if no member can serve the read yet.
+    async fn call_follower<F>(
+        &self,
+        key: &[u8],
+        mut request: F,
+    ) -> Result<Option<std::result::Result<Option<Value>, GetError>>>
+    where
+        F: FnMut(RegionEpoch) -> GetRequest,
+    {
+        let route = self.route(key).await?;
+        let n = route.peers.len();
+        let first = madsim::rand::thread_rng().gen_range(0..n);
+        for i in 0..n {
+            let addr = route.peers[(first + i) % n];
+            let req = request(route.region.epoch());
+            let rsp = match self.ep.call_timeout(addr, req, BACKOFF_TIME).await {
+                Ok(rsp) => rsp,
+                Err(e) => {
+                    tracing::debug!(%addr, ?e, "storage node unreachable");
+                    continue;
+                }
+            };
+            match rsp {
+                Err(GetError::DataNotReady { safe_ts }) => {
+                    tracing::debug!(%addr, safe_ts, "follower not ready");
+                }
+                Err(GetError::RegionChanged | GetError::NotLeader { .. }) => {}
+                rsp => return Ok(Some(rsp)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Routes the regions of the storage group of `route` to `leader`,
+    /// or to the next member of the group if the leader is unknown.
+    fn switch_leader(&self, route: &Route, leader: Option<SocketAddr>) {
+        let leader = leader.unwrap_or_else(|| {
+            let i = route.peers.iter().position(|a| *a == route.addr);
+            route.peers[i.map_or(0, |i| (i + 1) % route.peers.len())]
+        });
+        let mut routes = self.routes.lock().unwrap();
+        for r in routes.values_mut().filter(|r| r.peers == route.peers) {
+            r.addr = leader;
+        }
+    }
+
+    /// Returns the route of `key`, refreshing the routing table if it is unknown.
+    async fn route(&self, key: &[u8]) -> Result<Route> {
+        self.find_route(|| self.locate(key)).await
+    }
+
+    /// Returns the route of the region containing the keys right before `end`,
+    /// or the last region if `end` is empty.
+    async fn route_before(&self, end: &[u8]) -> Result<Route> {
+        self.find_route(|| self.locate_before(end)).await
+    }
+
+    async fn find_route(&self, locate: impl Fn() -> Option<Route>) -> Result<Route> {
+        for i in 0..ROUTE_RETRY_TIMES {
+            if let Some(route) = locate() {
+                return Ok(route);
+            }
+            if i > 0 {
+                // the region may be moving between storage nodes
+                madsim::time::sleep(BACKOFF_TIME).await;
+            }
+            self.refresh_routes().await;
+        }
+        Err(Error::other("no region for the key"))
+    }
+
+    /// Returns the route of `key` in the routing table.
+    fn locate(&self, key: &[u8]) -> Option<Route> {
+        let routes = self.routes.lock().unwrap();
+        let range = (Bound::Unbounded, Bound::Included(key));
+        let (_, route) = routes.range::<[u8], _>(range).next_back()?;
+        route.region.contains(key).then(|| route.clone())
+    }
+
+    fn locate_before(&self, end: &[u8]) -> Option<Route> {
+        let routes = self.routes.lock().unwrap();
+        let (_, route) = match end.is_empty() {
+            true => routes.iter().next_back()?,
+            false => routes
+                .range::<[u8], _>((Bound::Unbounded, Bound::Excluded(end)))
+                .next_back()?,
+        };
+        let region = &route.region;
+        let covered = region.end_key.is_empty() || (!end.is_empty() && end <= &region.end_key[..]);
+        covered.then(|| route.clone())
+    }
+
+    /// Rebuilds the routing table from the regions reported by the storage nodes.
+    async fn refresh_routes(&self) {
+        let mut found = vec![];
+        for &addr in &self.txn_addrs {
+            match self.call_with_retry(addr, || RegionsRequest {}).await {
+                Ok(rsp) => {
+                    let peers: Arc<[SocketAddr]> = match rsp.peers.is_empty() {
+                        true => Arc::new([addr]),
+                        false => rsp.peers.into(),
+                    };
+                    found.extend(rsp.regions.into_iter().map(|region| Route {
+                        region,
+                        addr: rsp.leader.unwrap_or(addr),
+                        peers: peers.clone(),
+                    }))
+                }
+                Err(e) => tracing::warn!(%addr, ?e, "failed to list regions"),
+            }
+        }
+        // A node may not know yet that its region has changed,
+        // so the newer one of the overlapping regions wins.
+        found.sort_by_key(|route| Reverse(route.region.version));
+        let mut routes = BTreeMap::new();
+        for route in found {
+            if routes
+                .values()
+                .all(|r: &Route| !r.region.overlaps(&route.region))
+            {
+                routes.insert(route.region.start_key.clone(), route);
+            }
+        }
+        *self.routes.lock().unwrap() = routes;
     }
 
     async fn call_with_retry<F, R>(&self, dst: SocketAddr, mut request: F) -> Result<R::Response>
@@ -185,3 +1390,27 @@ impl Client {
         Err(last_err.unwrap())
     }
 }
+
+/// Builds batches committing keys of the transaction started at `start_ts`.
+fn commit_request(
+    start_ts: u64,
+    commit_ts: u64,
+    is_primary: bool,
+) -> impl Fn(RegionEpoch, &[&Key]) -> BatchCommitRequest {
+    move |region, keys| BatchCommitRequest {
+        region,
+        is_primary,
+        keys: keys.iter().map(|&key| key.clone()).collect(),
+        start_ts,
+        commit_ts,
+    }
+}
+
+/// Builds batches rolling back keys of the transaction started at `start_ts`.
+fn rollback_request(start_ts: u64) -> impl Fn(RegionEpoch, &[&Key]) -> BatchRollbackRequest {
+    move |region, keys| BatchRollbackRequest {
+        region,
+        keys: keys.iter().map(|&key| key.clone()).collect(),
+        start_ts,
+    }
+}
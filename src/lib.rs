@@ -1,3 +1,7 @@
 pub mod client;
+mod meta;
 pub mod msg;
+mod raft;
 pub mod server;
+pub mod storage;
+mod wal;
@@ -0,0 +1,118 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use madsim::fs::{self, File};
+use serde::{de::DeserializeOwned, Serialize};
+
+// The size of the slot header: sequence number (8), payload length (4) and checksum (4).
+const HEADER_SIZE: usize = 16;
+
+/// SlotFile durably stores a value that is replaced as a whole.
+///
+/// There is no atomic rename in the simulated file system, so the value is
+/// written to two slot files in turn. A crash while writing one slot leaves
+/// the previous value intact in the other, and recovery picks the valid slot
+/// with the highest sequence number.
+pub struct SlotFile {
+    paths: [PathBuf; 2],
+    seq: u64,
+}
+
+impl SlotFile {
+    /// Opens the slot files at `path` and returns the last saved value, if any.
+    pub async fn open<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<(Self, Option<T>)> {
+        let path = path.as_ref();
+        let paths = [path.with_extension("0"), path.with_extension("1")];
+        let mut latest = None;
+        for path in &paths {
+            let slot = match fs::read(path).await {
+                Ok(data) => decode_slot::<T>(&data),
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            };
+            if let Some((seq, value)) = slot {
+                if latest.as_ref().is_none_or(|(s, _)| seq > *s) {
+                    latest = Some((seq, value));
+                }
+            }
+        }
+        let seq = latest.as_ref().map_or(0, |(seq, _)| *seq);
+        Ok((SlotFile { paths, seq }, latest.map(|(_, value)| value)))
+    }
+
+    /// Durably replaces the value.
+    pub async fn save<T: Serialize>(&mut self, value: &T) -> Result<()> {
+        let seq = self.seq + 1;
+        let payload = bincode::serialize(value).map_err(Error::other)?;
+        let mut data = Vec::with_capacity(HEADER_SIZE + payload.len());
+        data.extend_from_slice(&seq.to_le_bytes());
+        data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        data.extend_from_slice(&checksum(&payload).to_le_bytes());
+        data.extend_from_slice(&payload);
+        write_file(&self.paths[(seq % 2) as usize], &data).await?;
+        self.seq = seq;
+        Ok(())
+    }
+}
+
+/// MetaFile durably stores a small value and keeps a copy of it in memory.
+pub struct MetaFile<T> {
+    slots: SlotFile,
+    value: T,
+}
+
+impl<T: Serialize + DeserializeOwned + Default> MetaFile<T> {
+    /// Opens the meta file at `path`, or returns the default value if it has never been saved.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let (slots, value) = SlotFile::open(path).await?;
+        Ok(MetaFile {
+            slots,
+            value: value.unwrap_or_default(),
+        })
+    }
+
+    /// Returns the last saved value.
+    pub fn get(&self) -> &T {
+        &self.value
+    }
+
+    /// Durably replaces the value.
+    pub async fn save(&mut self, value: T) -> Result<()> {
+        self.slots.save(&value).await?;
+        self.value = value;
+        Ok(())
+    }
+}
+
+fn decode_slot<T: DeserializeOwned>(data: &[u8]) -> Option<(u64, T)> {
+    let header = data.get(..HEADER_SIZE)?;
+    let seq = u64::from_le_bytes(header[0..8].try_into().unwrap());
+    let len = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+    let sum = u32::from_le_bytes(header[12..16].try_into().unwrap());
+    let payload = data.get(HEADER_SIZE..HEADER_SIZE + len)?;
+    if checksum(payload) != sum {
+        return None;
+    }
+    Some((seq, bincode::deserialize(payload).ok()?))
+}
+
+/// Creates or truncates the file at `path`, writes `data` into it and syncs it to disk.
+pub(crate) async fn write_file(path: &Path, data: &[u8]) -> Result<()> {
+    // `write_all_at` takes `&mut self` outside the simulator.
+    #[allow(unused_mut)]
+    let mut file = File::create(path).await?;
+    file.write_all_at(data, 0).await?;
+    file.sync_all().await
+}
+
+/// Computes the CRC-32 (IEEE) checksum of `data`.
+pub(crate) fn checksum(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
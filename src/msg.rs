@@ -1,36 +1,263 @@
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
 use madsim::Request;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::storage::{Column, Value};
+
+/// The number of low bits holding the logical part of a hybrid timestamp.
+/// The remaining high bits hold the physical time in milliseconds since the Unix epoch.
+pub const LOGICAL_BITS: u32 = 18;
+
+/// Composes a hybrid timestamp from its physical (milliseconds) and logical parts.
+pub fn compose_ts(physical: u64, logical: u64) -> u64 {
+    debug_assert!(logical < 1 << LOGICAL_BITS, "logical part overflow");
+    (physical << LOGICAL_BITS) | logical
+}
+
+/// Extracts the physical time (milliseconds since the Unix epoch) of a hybrid timestamp.
+pub fn extract_physical(ts: u64) -> u64 {
+    ts >> LOGICAL_BITS
+}
+
+/// Extracts the logical counter of a hybrid timestamp.
+pub fn extract_logical(ts: u64) -> u64 {
+    ts & ((1 << LOGICAL_BITS) - 1)
+}
+
+/// Returns the physical time in milliseconds since the Unix epoch.
+pub fn physical_now() -> u64 {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+    now.expect("clock before unix epoch").as_millis() as u64
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Request)]
-#[rtype("TimestampResponse")]
-pub struct TimestampRequest {}
+#[rtype("Result<TimestampResponse, TsoError>")]
+pub struct TimestampRequest {
+    /// The number of contiguous timestamps to allocate. Zero is treated as one.
+    pub count: u32,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TimestampResponse {
+    /// The first timestamp of the allocated range `[ts, ts + count)`.
     pub ts: u64,
+    pub count: u32,
+}
+
+#[derive(Error, Debug, Clone, Serialize, Deserialize)]
+pub enum TsoError {
+    #[error("not the leader of the TSO group, the leader may be {leader:?}")]
+    NotLeader { leader: Option<SocketAddr> },
+}
+
+/// Asks a TSO group member to vote for a candidate.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("TsoVoteResponse")]
+pub struct TsoVoteRequest {
+    pub term: u64,
+    pub candidate: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TsoVoteResponse {
+    pub term: u64,
+    pub granted: bool,
+    /// The high-water mark persisted on the voter.
+    pub limit: u64,
+}
+
+/// Replicates the high-water mark of the TSO leader and renews its lease.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("TsoAppendResponse")]
+pub struct TsoAppendRequest {
+    pub term: u64,
+    pub leader: usize,
+    pub limit: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TsoAppendResponse {
+    pub term: u64,
+    pub success: bool,
+}
+
+/// Region is a range of keys owned by a storage node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Region {
+    pub id: u64,
+    pub start_key: Vec<u8>,
+    /// The end of the range (exclusive). An empty key means unbounded.
+    pub end_key: Vec<u8>,
+    /// Increased on every split and merge.
+    pub version: u64,
+}
+
+impl Region {
+    /// Returns whether `key` is in the region.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.start_key.as_slice() <= key && (self.end_key.is_empty() || key < &self.end_key[..])
+    }
+
+    /// Returns whether the region overlaps with `other`.
+    pub fn overlaps(&self, other: &Region) -> bool {
+        (self.end_key.is_empty() || other.start_key < self.end_key)
+            && (other.end_key.is_empty() || self.start_key < other.end_key)
+    }
+
+    /// Returns the identity and version of the region.
+    pub fn epoch(&self) -> RegionEpoch {
+        RegionEpoch {
+            id: self.id,
+            version: self.version,
+        }
+    }
+}
+
+/// The region a request is routed to, as known by the client.
+///
+/// A storage node rejects the request with a "region changed" error unless
+/// it owns the region with the same version and the region contains the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegionEpoch {
+    pub id: u64,
+    pub version: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Request)]
 #[rtype("Result<Option<Vec<u8>>, GetError>")]
 pub struct GetRequest {
+    pub region: RegionEpoch,
     pub start_ts: u64,
     pub key: Vec<u8>,
+    /// Lets a follower of a storage group serve the request.
+    pub follower_read: bool,
 }
 
 #[derive(Error, Debug, Clone, Serialize, Deserialize)]
 pub enum GetError {
     #[error("key is locked by timestamp {ts}")]
-    IsLocked { ts: u64, primary: Vec<u8> },
+    IsLocked {
+        ts: u64,
+        primary: Vec<u8>,
+        async_commit: bool,
+    },
+    #[error("region changed")]
+    RegionChanged,
+    /// A follower has not applied every write below the timestamp yet.
+    #[error("data is not ready, the safe timestamp is {safe_ts}")]
+    DataNotReady { safe_ts: u64 },
+    #[error("not the leader of the storage group, the leader may be {leader:?}")]
+    NotLeader { leader: Option<SocketAddr> },
+}
+
+/// Reads the latest values of several keys in a region.
+///
+/// A locked key gets its own error, and the other keys are still read.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("Result<Vec<Result<Option<Vec<u8>>, GetError>>, GetError>")]
+pub struct BatchGetRequest {
+    pub region: RegionEpoch,
+    pub start_ts: u64,
+    pub keys: Vec<Vec<u8>>,
+}
+
+/// A key and its value.
+pub type KvPair = (Vec<u8>, Vec<u8>);
+
+/// Reads the latest values of the keys from `start_key` up to `end_key` in a region.
+///
+/// At most `limit` keys are returned, in descending order if `reverse` is set.
+/// An empty `end_key` means the end of the region.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("Result<Vec<KvPair>, ScanError>")]
+pub struct ScanRequest {
+    pub region: RegionEpoch,
+    pub start_ts: u64,
+    pub start_key: Vec<u8>,
+    pub end_key: Vec<u8>,
+    pub limit: usize,
+    pub reverse: bool,
+}
+
+#[derive(Error, Debug, Clone, Serialize, Deserialize)]
+pub enum ScanError {
+    /// The scan stops at the first lock found, which the client should resolve.
+    #[error("key {key:?} is locked by timestamp {ts}")]
+    IsLocked {
+        key: Vec<u8>,
+        ts: u64,
+        primary: Vec<u8>,
+        async_commit: bool,
+    },
+    #[error("region changed")]
+    RegionChanged,
+    #[error("not the leader of the storage group, the leader may be {leader:?}")]
+    NotLeader { leader: Option<SocketAddr> },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Request)]
 #[rtype("Result<(), PrewriteError>")]
 pub struct PrewriteRequest {
+    pub region: RegionEpoch,
     pub start_ts: u64,
     pub key: Vec<u8>,
     pub value: Vec<u8>,
     pub primary_key: Vec<u8>,
+    /// How long the lock lives in milliseconds, counted from `start_time`.
+    pub lock_ttl: u64,
+    /// The physical time the transaction started at, in milliseconds since the Unix epoch.
+    pub start_time: u64,
+}
+
+/// Prewrites many keys of a region at once.
+///
+/// The request fails as a whole if the region has changed. Otherwise it
+/// returns the result of every key in order: the lowest timestamp the
+/// transaction may commit at if it uses async commit, or zero.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("Result<Vec<Result<u64, PrewriteError>>, PrewriteError>")]
+pub struct BatchPrewriteRequest {
+    pub region: RegionEpoch,
+    pub start_ts: u64,
+    pub mutations: Vec<Mutation>,
+    pub primary_key: Vec<u8>,
+    /// Whether the transaction counts as committed once all of its keys are prewritten.
+    pub async_commit: bool,
+    /// The other keys of an async-commit transaction, recorded in the lock of the primary key.
+    pub secondaries: Vec<Vec<u8>>,
+    /// A timestamp issued by TSO after an async-commit transaction is ready to commit,
+    /// below which it does not commit.
+    pub min_commit_ts: u64,
+    /// How long the locks live in milliseconds, counted from `start_time`.
+    pub lock_ttl: u64,
+    /// The physical time the transaction started at, in milliseconds since the Unix epoch.
+    pub start_time: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mutation {
+    pub key: Vec<u8>,
+    /// The new value of the key, or `None` to delete it.
+    pub value: Option<Vec<u8>>,
+}
+
+/// Commits a transaction whose keys are all owned by one storage node in one step.
+///
+/// The keys are checked for conflicts like a prewrite, then their data and
+/// write records are written at once without leaving any lock. Returns the
+/// commit timestamp, which is above any timestamp the node has served a read
+/// at, and not below `min_commit_ts`.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("Result<u64, PrewriteError>")]
+pub struct OnePhaseCommitRequest {
+    pub start_ts: u64,
+    /// A timestamp issued by TSO after the transaction is ready to commit.
+    pub min_commit_ts: u64,
+    /// The mutations of every region.
+    pub regions: Vec<(RegionEpoch, Vec<Mutation>)>,
 }
 
 #[derive(Error, Debug, Clone, Serialize, Deserialize)]
@@ -39,34 +266,333 @@ pub enum PrewriteError {
     WriteConflict { ts: u64 },
     #[error("key is locked by timestamp {ts}")]
     IsLocked { ts: u64 },
+    #[error("transaction already rolled back")]
+    AlreadyRolledBack,
+    #[error("region changed")]
+    RegionChanged,
+    #[error("not the leader of the storage group, the leader may be {leader:?}")]
+    NotLeader { leader: Option<SocketAddr> },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Request)]
 #[rtype("Result<(), CommitError>")]
 pub struct CommitRequest {
+    pub region: RegionEpoch,
     pub is_primary: bool,
     pub key: Vec<u8>,
     pub start_ts: u64,
     pub commit_ts: u64,
 }
 
+/// Commits many keys of a region at once, returning the result of every key.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("Result<Vec<Result<(), CommitError>>, CommitError>")]
+pub struct BatchCommitRequest {
+    pub region: RegionEpoch,
+    pub is_primary: bool,
+    pub keys: Vec<Vec<u8>>,
+    pub start_ts: u64,
+    pub commit_ts: u64,
+}
+
 #[derive(Error, Debug, Clone, Serialize, Deserialize)]
-pub enum CommitError {}
+pub enum CommitError {
+    #[error("lock not found")]
+    LockNotFound,
+    #[error("key is locked by another transaction with timestamp {ts}")]
+    TxnLockMismatch { ts: u64 },
+    #[error("transaction already rolled back")]
+    AlreadyRolledBack,
+    #[error("region changed")]
+    RegionChanged,
+    #[error("not the leader of the storage group, the leader may be {leader:?}")]
+    NotLeader { leader: Option<SocketAddr> },
+}
 
-/// Check if the given key is committed. If so, return the commit timestamp.
+/// Check if the transaction of the given primary key is committed.
 #[derive(Debug, Clone, Serialize, Deserialize, Request)]
-#[rtype("Option<u64>")]
+#[rtype("Result<TxnStatus, CheckError>")]
 pub struct CheckRequest {
+    pub region: RegionEpoch,
     pub key: Vec<u8>,
     pub lock_ts: u64,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TxnStatus {
+    Committed(u64),
+    Uncommitted,
+    /// The primary key is locked by an async-commit transaction, which is
+    /// committed if all of its secondary keys are locked or committed.
+    AsyncCommit {
+        min_commit_ts: u64,
+        secondaries: Vec<Vec<u8>>,
+    },
+    /// The primary key is locked by a transaction still alive, whose lock
+    /// lives `ttl` milliseconds from its start time.
+    Locked {
+        ttl: u64,
+    },
+    RolledBack,
+}
+
+/// Checks the status of the transaction started at `lock_ts` on its primary
+/// key, and decides its fate in one step if it is abandoned.
+///
+/// A lock that has lived out its TTL by `current_time`, the physical time of
+/// the caller in milliseconds since the Unix epoch, is rolled back, unless
+/// the transaction uses async commit. A lock still alive is reported as
+/// `Locked`, and the caller should wait for it. If there is no lock, a
+/// rollback record is written when `rollback_if_not_exist` is set, so that
+/// the transaction can never commit, and `Uncommitted` is returned otherwise.
+///
+/// The secondary keys of an async-commit transaction are checked the same
+/// way, as each of them decides whether the transaction can commit.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("Result<TxnStatus, CheckError>")]
+pub struct CheckTxnStatusRequest {
+    pub region: RegionEpoch,
+    pub primary_key: Vec<u8>,
+    pub lock_ts: u64,
+    pub current_time: u64,
+    pub rollback_if_not_exist: bool,
+}
+
+/// Keeps the transaction started at `start_ts` alive by extending the TTL of
+/// the lock on its primary key to `advise_ttl` milliseconds, counted from its
+/// start time. Returns the TTL of the lock, which never shrinks.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("Result<u64, HeartBeatError>")]
+pub struct TxnHeartBeatRequest {
+    pub region: RegionEpoch,
+    pub primary_key: Vec<u8>,
+    pub start_ts: u64,
+    pub advise_ttl: u64,
+}
+
+#[derive(Error, Debug, Clone, Serialize, Deserialize)]
+pub enum HeartBeatError {
+    #[error("lock not found")]
+    LockNotFound,
+    #[error("region changed")]
+    RegionChanged,
+    #[error("not the leader of the storage group, the leader may be {leader:?}")]
+    NotLeader { leader: Option<SocketAddr> },
+}
+
+/// Checks the locks of many keys of a region left by the transaction started at `start_ts`.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("Result<Vec<Result<LockStatus, CheckError>>, CheckError>")]
+pub struct CheckLocksRequest {
+    pub region: RegionEpoch,
+    pub keys: Vec<Vec<u8>>,
+    pub start_ts: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LockStatus {
+    Locked { min_commit_ts: u64 },
+    Committed { commit_ts: u64 },
+    Missing,
+}
+
+#[derive(Error, Debug, Clone, Serialize, Deserialize)]
+pub enum CheckError {
+    #[error("region changed")]
+    RegionChanged,
+    #[error("not the leader of the storage group, the leader may be {leader:?}")]
+    NotLeader { leader: Option<SocketAddr> },
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Request)]
 #[rtype("Result<(), RollbackError>")]
 pub struct RollbackRequest {
+    pub region: RegionEpoch,
     pub key: Vec<u8>,
     pub start_ts: u64,
 }
 
+/// Rolls back many keys of a region at once, returning the result of every key.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("Result<Vec<Result<(), RollbackError>>, RollbackError>")]
+pub struct BatchRollbackRequest {
+    pub region: RegionEpoch,
+    pub keys: Vec<Vec<u8>>,
+    pub start_ts: u64,
+}
+
+#[derive(Error, Debug, Clone, Serialize, Deserialize)]
+pub enum RollbackError {
+    #[error("transaction already committed with timestamp {commit_ts}")]
+    AlreadyCommitted { commit_ts: u64 },
+    #[error("region changed")]
+    RegionChanged,
+    #[error("not the leader of the storage group, the leader may be {leader:?}")]
+    NotLeader { leader: Option<SocketAddr> },
+}
+
+/// Commits or rolls back the locks of the transaction started at `start_ts` in a region.
+///
+/// The locks are committed at `commit_ts` if there is one, and rolled back
+/// otherwise. Only the locks of `keys` are resolved, or every lock of the
+/// transaction in the region if `keys` is empty. Returns the number of locks
+/// resolved.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("Result<usize, ResolveError>")]
+pub struct ResolveLockRequest {
+    pub region: RegionEpoch,
+    pub start_ts: u64,
+    pub commit_ts: Option<u64>,
+    pub keys: Vec<Vec<u8>>,
+}
+
+#[derive(Error, Debug, Clone, Serialize, Deserialize)]
+pub enum ResolveError {
+    #[error("region changed")]
+    RegionChanged,
+    #[error("not the leader of the storage group, the leader may be {leader:?}")]
+    NotLeader { leader: Option<SocketAddr> },
+}
+
+/// Reports the counters of the lock collector of a storage node.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("LockGcStats")]
+pub struct LockGcStatsRequest {}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockGcStats {
+    /// The number of expired locks found by the scans, each counted once.
+    pub found: u64,
+    /// The number of locks committed or rolled back by the collector.
+    pub cleaned: u64,
+}
+
+/// Lists the regions owned by a storage node.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("RegionsResponse")]
+pub struct RegionsRequest {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegionsResponse {
+    /// The addresses of all members of the storage group, empty for a single node.
+    pub peers: Vec<SocketAddr>,
+    /// The leader of the storage group as known by the node.
+    pub leader: Option<SocketAddr>,
+    pub regions: Vec<Region>,
+}
+
+/// Splits a region at `split_key`.
+///
+/// The keys from `split_key` on form a new region with `new_region_id`,
+/// which moves to the storage node at `target` if there is one.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("Result<(), RegionError>")]
+pub struct SplitRequest {
+    pub region: RegionEpoch,
+    pub split_key: Vec<u8>,
+    pub new_region_id: u64,
+    pub target: Option<SocketAddr>,
+}
+
+/// Merges a region into an adjacent region `into` owned by the storage node at `target`.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("Result<(), RegionError>")]
+pub struct MergeRequest {
+    pub region: RegionEpoch,
+    pub into: RegionEpoch,
+    pub target: SocketAddr,
+}
+
+/// Moves a region with all its records to another storage node.
+///
+/// The region is installed as a new region, or merged into the region
+/// `merge_into` if there is one.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("Result<(), RegionError>")]
+pub struct InstallRegionRequest {
+    pub region: Region,
+    pub merge_into: Option<u64>,
+    pub records: Vec<(Column, Vec<u8>, u64, Value)>,
+    /// The safe timestamp of the source node, which has served no read of
+    /// the region above it.
+    pub safe_ts: u64,
+}
+
 #[derive(Error, Debug, Clone, Serialize, Deserialize)]
-pub enum RollbackError {}
+pub enum RegionError {
+    #[error("region changed")]
+    RegionChanged,
+    #[error("key is not inside the region")]
+    InvalidKey,
+    #[error("regions are not adjacent")]
+    NotAdjacent,
+    #[error("storage node is unreachable")]
+    Unreachable,
+    #[error("not the leader of the storage group, the leader may be {leader:?}")]
+    NotLeader { leader: Option<SocketAddr> },
+}
+
+/// An entry in the replicated log of a storage group.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RaftEntry {
+    pub term: u64,
+    /// The encoded command, or None for the entry appended by a new leader.
+    pub command: Option<Vec<u8>>,
+}
+
+/// Asks a storage group member to vote for a candidate.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("RaftVoteResponse")]
+pub struct RaftVoteRequest {
+    pub term: u64,
+    pub candidate: usize,
+    pub last_index: u64,
+    pub last_term: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RaftVoteResponse {
+    pub term: u64,
+    pub granted: bool,
+}
+
+/// Replicates the log of a storage group leader, following the entry at `prev_index`.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("RaftAppendResponse")]
+pub struct RaftAppendRequest {
+    pub term: u64,
+    pub leader: usize,
+    pub prev_index: u64,
+    pub prev_term: u64,
+    pub entries: Vec<RaftEntry>,
+    /// The index of the last entry committed by the leader.
+    pub commit: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RaftAppendResponse {
+    pub term: u64,
+    pub success: bool,
+    /// The index of the last entry matching the leader on success.
+    /// Otherwise an index from which the leader may retry.
+    pub last_index: u64,
+}
+
+/// Replaces the state of a lagging storage group member with a snapshot of the leader.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("RaftSnapshotResponse")]
+pub struct RaftSnapshotRequest {
+    pub term: u64,
+    pub leader: usize,
+    /// The index and term of the last entry reflected in the snapshot.
+    pub last_index: u64,
+    pub last_term: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RaftSnapshotResponse {
+    pub term: u64,
+    /// Whether the snapshot has been persisted, or is older than the applied state.
+    pub success: bool,
+}
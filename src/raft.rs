@@ -0,0 +1,832 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use madsim::net::rpc::Request;
+use madsim::net::Endpoint;
+use madsim::rand::Rng;
+use madsim::time::Instant;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::msg::*;
+use crate::wal::Wal;
+
+// RAFT_HEARTBEAT_INTERVAL is the interval at which a leader replicates its log.
+const RAFT_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+// RAFT_ELECTION_TIMEOUT is the range of the randomized election timeout in milliseconds.
+const RAFT_ELECTION_TIMEOUT: Range<u64> = 300..600;
+// RAFT_MAX_ENTRIES is the maximum number of entries sent in one append request.
+const RAFT_MAX_ENTRIES: usize = 64;
+// RAFT_LOG_LIMIT is the number of applied entries that triggers compacting the log.
+const RAFT_LOG_LIMIT: u64 = 1000;
+
+/// StateMachine is the state replicated by a Raft group.
+pub trait StateMachine: Send + 'static {
+    type Command: Serialize + DeserializeOwned + Send;
+
+    /// Whether the applied state survives a restart once synced.
+    ///
+    /// The log keeps every entry of a state machine that is not durable,
+    /// since it is rebuilt by applying them all again.
+    const DURABLE: bool;
+
+    /// Applies the committed entry at `index` and returns the output for its proposer.
+    ///
+    /// `command` is None for the entry a new leader appends to commit the
+    /// entries of previous terms.
+    fn apply(&mut self, index: u64, command: Option<Self::Command>) -> Box<dyn Any + Send>;
+
+    /// Returns the index of the last applied entry.
+    fn applied(&self) -> u64;
+
+    /// Makes the index of the last applied entry durable along with the next `sync`.
+    ///
+    /// Until then, the index may go back after a restart, as long as the
+    /// entries are applied the same way again.
+    fn checkpoint(&mut self);
+
+    /// Encodes the whole state.
+    fn snapshot(&self) -> Vec<u8>;
+
+    /// Replaces the whole state with a snapshot taken at `index`.
+    fn restore(&mut self, index: u64, snapshot: &[u8]);
+
+    /// Returns a future that resolves once the applied state is durable.
+    fn sync(&mut self) -> impl Future<Output = io::Result<()>> + Send + 'static;
+}
+
+/// An error telling that a command was proposed to a member other than the leader.
+#[derive(Debug)]
+pub struct NotLeader {
+    pub leader: Option<SocketAddr>,
+}
+
+/// Raft replicates the commands applied to a state machine across a group.
+///
+/// A command proposed to the leader is appended to its log, replicated to
+/// the followers, and applied by every member in the same order once a
+/// majority has persisted it. Running alone, a command is applied at once.
+pub struct Raft<M> {
+    state: futures::lock::Mutex<RaftState<M>>,
+    // Addresses of all members of the group, including this one.
+    peers: Vec<SocketAddr>,
+    me: usize,
+    // The endpoint of a group member, used both to serve and to call other members.
+    ep: Option<Endpoint>,
+    wal: Option<Wal<RaftLog>>,
+}
+
+struct RaftState<M> {
+    sm: M,
+    // The persisted log, along with the current term and vote.
+    log: RaftLog,
+    role: Role,
+    // The index of the last entry known to be committed.
+    commit: u64,
+    // The next entry to send to each member, only on a leader.
+    next: Vec<u64>,
+    // The last entry persisted by each member, only on a leader.
+    matched: Vec<u64>,
+    // The proposers waiting for their entries to apply, by index, with the term of the entry.
+    waiters: BTreeMap<u64, (u64, oneshot::Sender<Box<dyn Any + Send>>)>,
+    election_deadline: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower { leader: Option<usize> },
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct RaftLog {
+    term: u64,
+    voted_for: Option<usize>,
+    // The index and term of the last entry compacted away.
+    snapshot_index: u64,
+    snapshot_term: u64,
+    entries: Vec<RaftEntry>,
+}
+
+/// A change of the log written to the write-ahead log.
+#[derive(Serialize, Deserialize)]
+enum LogRecord {
+    Vote { term: u64, voted_for: Option<usize> },
+    // Entries starting at `index`, replacing the entries from there on.
+    Append { index: u64, entries: Vec<RaftEntry> },
+}
+
+impl RaftLog {
+    fn last_index(&self) -> u64 {
+        self.snapshot_index + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries.last().map_or(self.snapshot_term, |e| e.term)
+    }
+
+    /// Returns the term of the entry at `index`, unless it is compacted away or missing.
+    fn term(&self, index: u64) -> Option<u64> {
+        match index.checked_sub(self.snapshot_index + 1) {
+            None if index == self.snapshot_index => Some(self.snapshot_term),
+            None => None,
+            Some(i) => self.entries.get(i as usize).map(|e| e.term),
+        }
+    }
+
+    fn entry(&self, index: u64) -> Option<&RaftEntry> {
+        let i = index.checked_sub(self.snapshot_index + 1)?;
+        self.entries.get(i as usize)
+    }
+
+    fn replay(&mut self, record: LogRecord) {
+        match record {
+            LogRecord::Vote { term, voted_for } => {
+                self.term = term;
+                self.voted_for = voted_for;
+            }
+            LogRecord::Append { index, entries } => {
+                let keep = index.saturating_sub(self.snapshot_index + 1) as usize;
+                let skip = (self.snapshot_index + 1).saturating_sub(index) as usize;
+                self.entries.truncate(keep);
+                self.entries.extend(entries.into_iter().skip(skip));
+            }
+        }
+    }
+
+    /// Drops the entries up to `index`, which must be in the log.
+    fn compact(&mut self, index: u64) {
+        let Some(term) = self.term(index) else {
+            return;
+        };
+        let count = (index - self.snapshot_index) as usize;
+        self.entries.drain(..count);
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+    }
+}
+
+impl<M: StateMachine> Raft<M> {
+    /// Creates a Raft running alone, which applies every command at once.
+    pub fn standalone(sm: M) -> Self {
+        Raft {
+            state: futures::lock::Mutex::new(RaftState::new(sm, RaftLog::default(), Role::Leader)),
+            peers: vec![],
+            me: 0,
+            ep: None,
+            wal: None,
+        }
+    }
+
+    /// Opens the `me`-th member of a group with the given `peers`, persisting its log under `dir`.
+    ///
+    /// `peers` contains the addresses of all members, including this one.
+    /// The member calls the others through `ep`, which must be bound to its
+    /// own address and serve the Raft requests.
+    pub async fn open_group(
+        sm: M,
+        dir: impl AsRef<Path>,
+        peers: Vec<SocketAddr>,
+        me: usize,
+        ep: Endpoint,
+    ) -> io::Result<Arc<Self>> {
+        let (wal, log) = Wal::open(dir, |mut log: RaftLog, records| {
+            for record in records {
+                log.replay(bincode::deserialize(&record).map_err(io::Error::other)?);
+            }
+            Ok(log)
+        })
+        .await?;
+        tracing::info!(
+            me,
+            term = log.term,
+            last_index = log.last_index(),
+            applied = sm.applied(),
+            "raft recovered"
+        );
+        let raft = Arc::new(Raft {
+            state: futures::lock::Mutex::new(RaftState::new(
+                sm,
+                log,
+                Role::Follower { leader: None },
+            )),
+            peers,
+            me,
+            ep: Some(ep),
+            wal: Some(wal),
+        });
+        madsim::task::spawn(raft.clone().run());
+        Ok(raft)
+    }
+
+    /// Returns the endpoint of a group member.
+    pub fn endpoint(&self) -> Option<&Endpoint> {
+        self.ep.as_ref()
+    }
+
+    /// Returns the addresses of all members, or nothing if running alone.
+    pub fn peers(&self) -> &[SocketAddr] {
+        &self.peers
+    }
+
+    pub async fn is_leader(&self) -> bool {
+        self.state.lock().await.role == Role::Leader
+    }
+
+    /// Returns the address of the leader as far as this member knows.
+    pub async fn leader(&self) -> Option<SocketAddr> {
+        let state = self.state.lock().await;
+        match state.role {
+            Role::Leader => self.peers.get(self.me).cloned(),
+            _ => self.not_leader(&state).leader,
+        }
+    }
+
+    /// Runs `f` on the state machine as applied by this member.
+    pub async fn read<R>(&self, f: impl FnOnce(&M) -> R) -> R {
+        f(&self.state.lock().await.sm)
+    }
+
+    /// Proposes a command and returns its output once it is committed and applied.
+    ///
+    /// The command may still be applied if the leader changes in the meantime,
+    /// or if this member fails to persist it, in which case the proposer gets
+    /// `NotLeader` instead of the output.
+    pub async fn propose(
+        self: &Arc<Self>,
+        command: M::Command,
+    ) -> Result<Box<dyn Any + Send>, NotLeader> {
+        let Some(wal) = &self.wal else {
+            let mut state = self.state.lock().await;
+            let index = state.sm.applied() + 1;
+            let output = state.sm.apply(index, Some(command));
+            let synced = state.sm.sync();
+            drop(state);
+            if let Err(e) = synced.await {
+                self.persist_failed(e).await;
+                return Err(NotLeader { leader: None });
+            }
+            return Ok(output);
+        };
+        let (rx, lsn, index, term) = {
+            let mut state = self.state.lock().await;
+            if state.role != Role::Leader {
+                return Err(self.not_leader(&state));
+            }
+            let term = state.log.term;
+            let index = state.log.last_index() + 1;
+            let entries = vec![RaftEntry {
+                term,
+                command: Some(bincode::serialize(&command).unwrap()),
+            }];
+            let lsn = state.record(wal, LogRecord::Append { index, entries });
+            let (tx, rx) = oneshot::channel();
+            state.waiters.insert(index, (term, tx));
+            (rx, lsn, index, term)
+        };
+        self.persisted(lsn, index, term).await;
+        self.replicate().await;
+        rx.await.map_err(|_| NotLeader { leader: None })
+    }
+
+    fn not_leader(&self, state: &RaftState<M>) -> NotLeader {
+        let leader = match state.role {
+            Role::Follower { leader } => leader.map(|i| self.peers[i]),
+            _ => None,
+        };
+        NotLeader { leader }
+    }
+
+    fn majority(&self) -> usize {
+        self.peers.len() / 2 + 1
+    }
+
+    /// Drives elections and heartbeats of a group member.
+    async fn run(self: Arc<Self>) {
+        loop {
+            madsim::time::sleep(RAFT_HEARTBEAT_INTERVAL).await;
+            let (role, deadline) = {
+                let state = self.state.lock().await;
+                (state.role, state.election_deadline)
+            };
+            match role {
+                Role::Leader => self.replicate().await,
+                _ if Instant::now() >= deadline => self.campaign().await,
+                _ => {}
+            }
+        }
+    }
+
+    /// Starts an election for a new term.
+    async fn campaign(self: &Arc<Self>) {
+        let wal = self.wal.as_ref().unwrap();
+        let (req, lsn) = {
+            let mut state = self.state.lock().await;
+            let term = state.log.term + 1;
+            let vote = LogRecord::Vote {
+                term,
+                voted_for: Some(self.me),
+            };
+            let lsn = state.record(wal, vote);
+            state.role = Role::Candidate;
+            state.election_deadline = Instant::now() + election_timeout();
+            tracing::info!(me = self.me, term, "raft campaign");
+            let req = RaftVoteRequest {
+                term,
+                candidate: self.me,
+                last_index: state.log.last_index(),
+                last_term: state.log.last_term(),
+            };
+            (req, lsn)
+        };
+        if let Err(e) = wal.flush(lsn).await {
+            self.persist_failed(e).await;
+            return;
+        }
+        let rsps = self.broadcast(|_| req.clone()).await;
+
+        let (lsn, index) = {
+            let mut state = self.state.lock().await;
+            if state.role != Role::Candidate || state.log.term != req.term {
+                return;
+            }
+            let mut votes = 1;
+            for (_, rsp) in rsps {
+                if rsp.term > req.term {
+                    state.step_down(wal, rsp.term, None);
+                    return;
+                }
+                votes += rsp.granted as usize;
+            }
+            if votes < self.majority() {
+                return;
+            }
+            tracing::info!(me = self.me, term = req.term, "raft become leader");
+            let last_index = state.log.last_index();
+            state.role = Role::Leader;
+            state.next = vec![last_index + 1; self.peers.len()];
+            state.matched = vec![0; self.peers.len()];
+            // Entries of previous terms are only committed along with one of the current term.
+            let entries = vec![RaftEntry {
+                term: req.term,
+                command: None,
+            }];
+            let index = last_index + 1;
+            (
+                state.record(wal, LogRecord::Append { index, entries }),
+                index,
+            )
+        };
+        self.persisted(lsn, index, req.term).await;
+        self.replicate().await;
+    }
+
+    /// Waits until the leader's own log is persisted up to `index` of `term`,
+    /// which has been written with `lsn`.
+    async fn persisted(self: &Arc<Self>, lsn: u64, index: u64, term: u64) {
+        let wal = self.wal.as_ref().unwrap();
+        if let Err(e) = wal.flush(lsn).await {
+            self.persist_failed(e).await;
+            return;
+        }
+        let mut state = self.state.lock().await;
+        if state.role != Role::Leader || state.log.term != term {
+            return;
+        }
+        let matched = &mut state.matched[self.me];
+        *matched = (*matched).max(index);
+        if self.advance_commit(&mut state) {
+            drop(state);
+            self.apply_committed().await;
+        }
+    }
+
+    /// Sends the missing entries to every follower.
+    async fn replicate(self: &Arc<Self>) {
+        let calls = (0..self.peers.len())
+            .filter(|&i| i != self.me)
+            .map(|i| self.replicate_to(i));
+        futures::future::join_all(calls).await;
+    }
+
+    async fn replicate_to(self: &Arc<Self>, peer: usize) {
+        let ep = self.ep.as_ref().unwrap();
+        let addr = self.peers[peer];
+        let timeout = RAFT_HEARTBEAT_INTERVAL * 2;
+        let state = self.state.lock().await;
+        if state.role != Role::Leader {
+            return;
+        }
+        let term = state.log.term;
+        let next = state.next[peer];
+        if next <= state.log.snapshot_index {
+            // the entries have been compacted away
+            let last_index = state.sm.applied();
+            let req = RaftSnapshotRequest {
+                term,
+                leader: self.me,
+                last_index,
+                last_term: state
+                    .log
+                    .term(last_index)
+                    .expect("applied entry not in log"),
+                data: state.sm.snapshot(),
+            };
+            drop(state);
+            let Ok(rsp) = ep.call_timeout(addr, req, timeout * 4).await else {
+                return;
+            };
+            let mut state = self.state.lock().await;
+            if rsp.term > term {
+                state.step_down(self.wal.as_ref().unwrap(), rsp.term, None);
+            } else if rsp.success && state.role == Role::Leader && state.log.term == term {
+                state.matched[peer] = state.matched[peer].max(last_index);
+                state.next[peer] = state.matched[peer] + 1;
+            }
+            return;
+        }
+        let entries = (next..=state.log.last_index())
+            .take(RAFT_MAX_ENTRIES)
+            .map(|index| state.log.entry(index).unwrap().clone())
+            .collect::<Vec<_>>();
+        let req = RaftAppendRequest {
+            term,
+            leader: self.me,
+            prev_index: next - 1,
+            prev_term: state.log.term(next - 1).unwrap(),
+            entries,
+            commit: state.commit,
+        };
+        drop(state);
+        let Ok(rsp) = ep.call_timeout(addr, req, timeout).await else {
+            return;
+        };
+        let mut state = self.state.lock().await;
+        if rsp.term > term {
+            state.step_down(self.wal.as_ref().unwrap(), rsp.term, None);
+            return;
+        }
+        if state.role != Role::Leader || state.log.term != term {
+            return;
+        }
+        if !rsp.success {
+            state.next[peer] = rsp.last_index + 1;
+            return;
+        }
+        state.matched[peer] = state.matched[peer].max(rsp.last_index);
+        state.next[peer] = state.matched[peer] + 1;
+        if self.advance_commit(&mut state) {
+            drop(state);
+            self.apply_committed().await;
+        }
+    }
+
+    /// Commits the entries persisted by a majority. Returns whether the commit index advanced.
+    fn advance_commit(&self, state: &mut RaftState<M>) -> bool {
+        let mut matched = state.matched.clone();
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.majority() - 1];
+        // Only an entry of the current term is committed by counting replicas.
+        if index <= state.commit || state.log.term(index) != Some(state.log.term) {
+            return false;
+        }
+        state.commit = index;
+        true
+    }
+
+    /// Applies the committed entries and hands the outputs to their proposers.
+    async fn apply_committed(self: &Arc<Self>) {
+        let mut state = self.state.lock().await;
+        let mut outputs = vec![];
+        let start = state.sm.applied();
+        while state.sm.applied() < state.commit {
+            let index = state.sm.applied() + 1;
+            let entry = state.log.entry(index).expect("committed entry not in log");
+            let term = entry.term;
+            let command = (entry.command.as_ref())
+                .map(|command| bincode::deserialize(command).expect("corrupted raft entry"));
+            let output = state.sm.apply(index, command);
+            if let Some((t, tx)) = state.waiters.remove(&index) {
+                // The entry of the proposer was replaced otherwise.
+                if t == term {
+                    outputs.push((tx, output));
+                }
+            }
+        }
+        let applied = state.sm.applied();
+        if applied == start {
+            return;
+        }
+        let synced = state.sm.sync();
+        drop(state);
+        if let Err(e) = synced.await {
+            self.persist_failed(e).await;
+            return;
+        }
+        for (tx, output) in outputs {
+            let _ = tx.send(output);
+        }
+        self.compact(applied).await;
+    }
+
+    /// Drops the entries up to `applied` once there are many, as they are durable in the state machine.
+    async fn compact(&self, applied: u64) {
+        let Some(wal) = &self.wal else {
+            return;
+        };
+        if !M::DURABLE {
+            return;
+        }
+        let mut state = self.state.lock().await;
+        if applied < state.log.snapshot_index + RAFT_LOG_LIMIT {
+            return;
+        }
+        // The applied index must be durable before the log forgets the entries.
+        state.sm.checkpoint();
+        let synced = state.sm.sync();
+        if let Err(e) = synced.await {
+            drop(state);
+            self.persist_failed(e).await;
+            return;
+        }
+        state.log.compact(applied);
+        tracing::debug!(me = self.me, applied, "raft log compacted");
+        let lsn = wal.rotate(state.log.clone());
+        drop(state);
+        if let Err(e) = wal.flush(lsn).await {
+            self.persist_failed(e).await;
+        }
+    }
+
+    /// Steps down once the log or the state machine fails to persist, and
+    /// fails the waiting proposals.
+    ///
+    /// A write-ahead log stays failed from then on, so the member never
+    /// leads, votes or acknowledges entries again until it restarts.
+    async fn persist_failed(&self, e: io::Error) {
+        tracing::error!(me = self.me, %e, "raft failed to persist");
+        let mut state = self.state.lock().await;
+        state.waiters.clear();
+        if let Some(wal) = &self.wal {
+            let term = state.log.term;
+            state.step_down(wal, term, None);
+        }
+    }
+
+    /// Sends a request to all other members and collects the responses that arrive in time.
+    async fn broadcast<R: Request>(
+        &self,
+        request: impl Fn(usize) -> R,
+    ) -> Vec<(usize, R::Response)> {
+        let ep = self.ep.as_ref().unwrap();
+        let calls = (0..self.peers.len()).filter(|&i| i != self.me).map(|i| {
+            let call = ep.call_timeout(self.peers[i], request(i), RAFT_HEARTBEAT_INTERVAL * 2);
+            async move { (i, call.await) }
+        });
+        futures::future::join_all(calls)
+            .await
+            .into_iter()
+            .filter_map(|(i, rsp)| Some((i, rsp.ok()?)))
+            .collect()
+    }
+
+    pub async fn vote(&self, req: RaftVoteRequest) -> RaftVoteResponse {
+        let Some(wal) = &self.wal else {
+            return RaftVoteResponse {
+                term: 0,
+                granted: false,
+            };
+        };
+        let rsp = {
+            let mut state = self.state.lock().await;
+            if req.term > state.log.term {
+                state.step_down(wal, req.term, None);
+            }
+            let log = &state.log;
+            let up_to_date = (req.last_term, req.last_index) >= (log.last_term(), log.last_index());
+            let granted = req.term == log.term
+                && up_to_date
+                && log.voted_for.is_none_or(|c| c == req.candidate);
+            if granted && log.voted_for.is_none() {
+                let vote = LogRecord::Vote {
+                    term: req.term,
+                    voted_for: Some(req.candidate),
+                };
+                state.record(wal, vote);
+                state.election_deadline = Instant::now() + election_timeout();
+            }
+            RaftVoteResponse {
+                term: state.log.term,
+                granted,
+            }
+        };
+        if let Err(e) = wal.flush(wal.last_lsn()).await {
+            self.persist_failed(e).await;
+            return RaftVoteResponse {
+                granted: false,
+                ..rsp
+            };
+        }
+        rsp
+    }
+
+    pub async fn append(self: &Arc<Self>, req: RaftAppendRequest) -> RaftAppendResponse {
+        let Some(wal) = &self.wal else {
+            return RaftAppendResponse {
+                term: 0,
+                success: false,
+                last_index: 0,
+            };
+        };
+        let rsp = {
+            let mut state = self.state.lock().await;
+            if req.term < state.log.term {
+                return RaftAppendResponse {
+                    term: state.log.term,
+                    success: false,
+                    last_index: state.log.last_index(),
+                };
+            }
+            state.follow(wal, req.term, req.leader);
+            let last_index = req.prev_index + req.entries.len() as u64;
+            // The entries up to the snapshot are committed and applied already.
+            let mut prev_index = req.prev_index;
+            let mut entries = req.entries.as_slice();
+            if prev_index < state.log.snapshot_index {
+                let skip = (state.log.snapshot_index - prev_index) as usize;
+                entries = entries.get(skip..).unwrap_or_default();
+                prev_index = state.log.snapshot_index;
+            }
+            let prev_term = match prev_index == req.prev_index {
+                true => req.prev_term,
+                false => state.log.snapshot_term,
+            };
+            match state.log.term(prev_index) {
+                Some(term) if term == prev_term => {
+                    // skip the entries already in the log
+                    let mut index = prev_index + 1;
+                    while let Some(entry) = entries.first() {
+                        if state.log.term(index) != Some(entry.term) {
+                            break;
+                        }
+                        entries = &entries[1..];
+                        index += 1;
+                    }
+                    if !entries.is_empty() {
+                        let entries = entries.to_vec();
+                        state.record(wal, LogRecord::Append { index, entries });
+                    }
+                    state.commit = state.commit.max(req.commit.min(last_index));
+                    RaftAppendResponse {
+                        term: req.term,
+                        success: true,
+                        last_index,
+                    }
+                }
+                term => RaftAppendResponse {
+                    term: req.term,
+                    success: false,
+                    // The committed entries always match the leader.
+                    last_index: match term {
+                        Some(_) => state.commit,
+                        None => state.log.last_index(),
+                    },
+                },
+            }
+        };
+        if let Err(e) = wal.flush(wal.last_lsn()).await {
+            self.persist_failed(e).await;
+            return RaftAppendResponse {
+                success: false,
+                ..rsp
+            };
+        }
+        if rsp.success {
+            self.apply_committed().await;
+        }
+        rsp
+    }
+
+    pub async fn install_snapshot(&self, req: RaftSnapshotRequest) -> RaftSnapshotResponse {
+        let Some(wal) = &self.wal else {
+            return RaftSnapshotResponse {
+                term: 0,
+                success: false,
+            };
+        };
+        let lsn = {
+            let mut state = self.state.lock().await;
+            if req.term < state.log.term {
+                return RaftSnapshotResponse {
+                    term: state.log.term,
+                    success: false,
+                };
+            }
+            state.follow(wal, req.term, req.leader);
+            if req.last_index > state.sm.applied() {
+                tracing::info!(
+                    me = self.me,
+                    last_index = req.last_index,
+                    "raft install snapshot"
+                );
+                state.sm.restore(req.last_index, &req.data);
+                state.commit = state.commit.max(req.last_index);
+                // The state must be durable before the log forgets the entries.
+                let synced = state.sm.sync();
+                if let Err(e) = synced.await {
+                    drop(state);
+                    self.persist_failed(e).await;
+                    return RaftSnapshotResponse {
+                        term: req.term,
+                        success: false,
+                    };
+                }
+                if state.log.term(req.last_index) == Some(req.last_term) {
+                    state.log.compact(req.last_index);
+                } else {
+                    state.log.entries.clear();
+                    state.log.snapshot_index = req.last_index;
+                    state.log.snapshot_term = req.last_term;
+                }
+                wal.rotate(state.log.clone());
+            }
+            wal.last_lsn()
+        };
+        if let Err(e) = wal.flush(lsn).await {
+            self.persist_failed(e).await;
+            return RaftSnapshotResponse {
+                term: req.term,
+                success: false,
+            };
+        }
+        RaftSnapshotResponse {
+            term: req.term,
+            success: true,
+        }
+    }
+}
+
+impl<M> RaftState<M> {
+    fn new(sm: M, log: RaftLog, role: Role) -> Self
+    where
+        M: StateMachine,
+    {
+        RaftState {
+            commit: sm.applied(),
+            sm,
+            log,
+            role,
+            next: vec![],
+            matched: vec![],
+            waiters: BTreeMap::new(),
+            election_deadline: Instant::now() + election_timeout(),
+        }
+    }
+
+    /// Changes the log and writes the change to `wal`. Returns its LSN.
+    fn record(&mut self, wal: &Wal<RaftLog>, record: LogRecord) -> u64 {
+        let lsn = wal.append(bincode::serialize(&record).unwrap());
+        self.log.replay(record);
+        lsn
+    }
+
+    /// Becomes a follower of `term`.
+    fn step_down(&mut self, wal: &Wal<RaftLog>, term: u64, leader: Option<usize>) {
+        if term > self.log.term {
+            let vote = LogRecord::Vote {
+                term,
+                voted_for: None,
+            };
+            self.record(wal, vote);
+        }
+        if self.role == Role::Leader {
+            tracing::info!(term, "raft step down");
+        }
+        self.role = Role::Follower { leader };
+    }
+
+    /// Follows the leader of `term`, which must not be older than the current term.
+    fn follow(&mut self, wal: &Wal<RaftLog>, term: u64, leader: usize) {
+        if self.role
+            != (Role::Follower {
+                leader: Some(leader),
+            })
+            || term > self.log.term
+        {
+            self.step_down(wal, term, Some(leader));
+        }
+        self.election_deadline = Instant::now() + election_timeout();
+    }
+}
+
+fn election_timeout() -> Duration {
+    Duration::from_millis(madsim::rand::thread_rng().gen_range(RAFT_ELECTION_TIMEOUT))
+}
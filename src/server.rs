@@ -1,259 +1,1992 @@
-use std::collections::BTreeMap;
-use std::fmt::Display;
-use std::ops::{Bound, RangeBounds};
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::{Bound, Range};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 use itertools::Itertools;
+use madsim::net::rpc::Request;
+use madsim::net::Endpoint;
+use madsim::rand::Rng;
+use madsim::time::Instant;
+use serde::{Deserialize, Serialize};
 
+use crate::client::{RouteError, Router};
+use crate::meta::MetaFile;
 use crate::msg::*;
+use crate::raft::{Raft, StateMachine};
+use crate::storage::{Column, KvTable, Lock, Storage, Value, Write, WriteKind};
 
-#[derive(Default, Clone)]
+// TSO_WINDOW is the number of timestamps reserved at a time.
+// A restarted TSO or a new leader skips the unused part of the last window.
+const TSO_WINDOW: u64 = 1000;
+// TSO_HYBRID_WINDOW is the span of physical time reserved at a time with a hybrid clock.
+const TSO_HYBRID_WINDOW: Duration = Duration::from_secs(3);
+// TSO_HEARTBEAT_INTERVAL is the interval at which a TSO leader renews its lease.
+const TSO_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+// TSO_LEASE is how long a TSO leader may serve after a majority acknowledged it.
+// A member that has heard from a leader within the lease refuses to vote, so
+// a new leader is never elected while the old one may still be serving.
+const TSO_LEASE: Duration = Duration::from_millis(300);
+// TSO_ELECTION_TIMEOUT is the range of the randomized election timeout.
+// It must be longer than the lease.
+const TSO_ELECTION_TIMEOUT: Range<u64> = 400..800;
+// LOCK_GC_INTERVAL is the interval at which a storage node scans for expired locks.
+const LOCK_GC_INTERVAL: Duration = Duration::from_secs(1);
+// TRANSFER_TIMEOUT is the timeout of moving a region to another storage node.
+// It doubles on every retry, up to TRANSFER_MAX_TIMEOUT.
+const TRANSFER_TIMEOUT: Duration = Duration::from_millis(500);
+// TRANSFER_MAX_TIMEOUT is the longest timeout of moving a region.
+const TRANSFER_MAX_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// TimestampOracle hands out strictly increasing timestamps.
+///
+/// It runs either alone or as a member of a replicated group. In a group the
+/// members elect a leader, which is the only one serving timestamps. Before
+/// handing out a timestamp, the leader replicates a high-water mark above it
+/// to a majority, and a new leader resumes above the highest mark of its voters.
+#[derive(Clone)]
 pub struct TimestampOracle {
-    next_ts: Arc<AtomicU64>,
+    state: Arc<futures::lock::Mutex<TsoState>>,
+    // Addresses of all members of the group, including this one.
+    peers: Arc<[SocketAddr]>,
+    me: usize,
+    // The endpoint of a group member, used both to serve and to call other members.
+    ep: Option<Endpoint>,
+    hybrid: bool,
+}
+
+struct TsoState {
+    next_ts: u64,
+    // The persisted state. Every timestamp handed out is below `meta.limit`.
+    meta: MetaFile<TsoMeta>,
+    role: TsoRole,
+    // The high-water mark acknowledged by a majority. Only a leader may hand
+    // out timestamps below it.
+    committed_limit: u64,
+    lease_until: Option<Instant>,
+    // The last time this member heard from a leader or granted a vote.
+    last_heard: Instant,
+    election_deadline: Instant,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct TsoMeta {
+    term: u64,
+    voted_for: Option<usize>,
+    limit: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TsoRole {
+    Follower { leader: Option<usize> },
+    Candidate,
+    Leader,
+}
+
+impl TimestampOracle {
+    /// Opens a standalone TSO that persists its high-water mark under `dir`.
+    ///
+    /// After a restart the TSO resumes from the persisted mark, so it never
+    /// hands out a timestamp that may have been issued before.
+    pub async fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_group(dir, vec![], 0).await
+    }
+
+    /// Opens the `me`-th member of a TSO group with the given `peers`.
+    ///
+    /// `peers` contains the addresses of all members, including this one.
+    /// The member binds its own address, so it must be served by `serve_group`.
+    pub async fn open_group(
+        dir: impl AsRef<Path>,
+        peers: Vec<SocketAddr>,
+        me: usize,
+    ) -> io::Result<Self> {
+        let meta = MetaFile::<TsoMeta>::open(dir.as_ref().join("tso")).await?;
+        let next_ts = meta.get().limit;
+        tracing::info!(next_ts, term = meta.get().term, "tso recovered");
+        let now = Instant::now();
+        let standalone = peers.len() <= 1;
+        let state = TsoState {
+            next_ts,
+            meta,
+            role: match standalone {
+                true => TsoRole::Leader,
+                false => TsoRole::Follower { leader: None },
+            },
+            committed_limit: next_ts,
+            lease_until: None,
+            // A restarted member may have acknowledged a leader right before
+            // crashing, so it waits for a whole lease before voting.
+            last_heard: now,
+            election_deadline: now + election_timeout(),
+        };
+        let ep = match standalone {
+            true => None,
+            false => Some(Endpoint::bind(peers[me]).await?),
+        };
+        let tso = TimestampOracle {
+            state: Arc::new(futures::lock::Mutex::new(state)),
+            peers: peers.into(),
+            me,
+            ep,
+            hybrid: false,
+        };
+        if !standalone {
+            madsim::task::spawn(tso.clone().run());
+        }
+        Ok(tso)
+    }
+
+    /// Makes the TSO issue hybrid timestamps.
+    ///
+    /// A hybrid timestamp is composed of the physical time and a logical counter,
+    /// see `msg::compose_ts`. Timestamps stay strictly increasing even if the
+    /// clock goes backwards: the logical counter keeps counting from the last
+    /// timestamp until the clock catches up.
+    pub fn with_hybrid_clock(mut self) -> Self {
+        self.hybrid = true;
+        self
+    }
+
+    /// Serves a member of a TSO group on its own address.
+    pub async fn serve_group(self) -> io::Result<()> {
+        match self.ep.clone() {
+            Some(ep) => self.serve_on(ep).await,
+            None => Err(io::Error::other("not a member of a tso group")),
+        }
+    }
+
+    fn standalone(&self) -> bool {
+        self.peers.len() <= 1
+    }
+
+    fn majority(&self) -> usize {
+        self.peers.len() / 2 + 1
+    }
+
+    /// Returns the first timestamp that may be handed out next and the size of a window.
+    fn next_ts(&self, state: &TsoState) -> (u64, u64) {
+        if !self.hybrid {
+            return (state.next_ts, TSO_WINDOW);
+        }
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("clock before unix epoch");
+        let ts = state.next_ts.max(compose_ts(now.as_millis() as u64, 0));
+        (ts, compose_ts(TSO_HYBRID_WINDOW.as_millis() as u64, 0))
+    }
+
+    fn not_leader(&self, state: &TsoState) -> TsoError {
+        let leader = match state.role {
+            TsoRole::Follower { leader } => leader.map(|i| self.peers[i]),
+            _ => None,
+        };
+        TsoError::NotLeader { leader }
+    }
+
+    /// Drives elections and heartbeats of a group member.
+    async fn run(self) {
+        loop {
+            madsim::time::sleep(TSO_HEARTBEAT_INTERVAL).await;
+            let (role, deadline) = {
+                let state = self.state.lock().await;
+                (state.role, state.election_deadline)
+            };
+            match role {
+                TsoRole::Leader => {
+                    let state = self.state.lock().await;
+                    let (ts, window) = self.next_ts(&state);
+                    // Keep at least half a window reserved ahead of the next timestamp.
+                    let mut limit = state.meta.get().limit;
+                    if limit < ts + window / 2 {
+                        limit = ts + window;
+                    }
+                    drop(state);
+                    self.replicate(limit).await;
+                }
+                _ if Instant::now() >= deadline => self.campaign().await,
+                _ => {}
+            }
+        }
+    }
+
+    /// Starts an election for a new term.
+    async fn campaign(&self) {
+        let req = {
+            let mut state = self.state.lock().await;
+            let mut meta = state.meta.get().clone();
+            meta.term += 1;
+            meta.voted_for = Some(self.me);
+            if let Err(e) = state.meta.save(meta.clone()).await {
+                tracing::warn!("failed to persist tso meta: {e}");
+                return;
+            }
+            state.role = TsoRole::Candidate;
+            state.election_deadline = Instant::now() + election_timeout();
+            tracing::info!(me = self.me, term = meta.term, "tso campaign");
+            TsoVoteRequest {
+                term: meta.term,
+                candidate: self.me,
+            }
+        };
+        let rsps = self.broadcast(|| req.clone()).await;
+
+        let mut state = self.state.lock().await;
+        if state.role != TsoRole::Candidate || state.meta.get().term != req.term {
+            return;
+        }
+        let mut votes = 1;
+        let mut limit = state.meta.get().limit;
+        for rsp in rsps {
+            if rsp.term > req.term {
+                self.step_down(&mut state, rsp.term, None).await;
+                return;
+            }
+            if rsp.granted {
+                votes += 1;
+                limit = limit.max(rsp.limit);
+            }
+        }
+        if votes >= self.majority() {
+            tracing::info!(me = self.me, term = req.term, limit, "tso become leader");
+            // Any timestamp issued by an old leader is below the mark
+            // acknowledged by its majority, which overlaps with our voters.
+            state.role = TsoRole::Leader;
+            state.next_ts = state.next_ts.max(limit);
+            state.committed_limit = state.next_ts;
+            state.lease_until = None;
+        }
+    }
+
+    /// Replicates a high-water mark of at least `limit` to a majority and renews the lease.
+    /// Returns false if this member is not the leader or failed to reach a majority.
+    async fn replicate(&self, limit: u64) -> bool {
+        let req = {
+            let mut state = self.state.lock().await;
+            if state.role != TsoRole::Leader {
+                return false;
+            }
+            let mut meta = state.meta.get().clone();
+            if limit > meta.limit {
+                meta.limit = limit;
+                if let Err(e) = state.meta.save(meta.clone()).await {
+                    tracing::warn!("failed to persist tso meta: {e}");
+                    return false;
+                }
+            }
+            TsoAppendRequest {
+                term: meta.term,
+                leader: self.me,
+                limit: meta.limit,
+            }
+        };
+        let start = Instant::now();
+        let rsps = self.broadcast(|| req.clone()).await;
+
+        let mut state = self.state.lock().await;
+        if state.role != TsoRole::Leader || state.meta.get().term != req.term {
+            return false;
+        }
+        let mut acks = 1;
+        for rsp in rsps {
+            if rsp.term > req.term {
+                self.step_down(&mut state, rsp.term, None).await;
+                return false;
+            }
+            acks += rsp.success as usize;
+        }
+        if acks < self.majority() {
+            return false;
+        }
+        state.committed_limit = state.committed_limit.max(req.limit);
+        state.lease_until = Some(start + TSO_LEASE);
+        true
+    }
+
+    /// Sends a request to all other members and collects the responses that arrive in time.
+    async fn broadcast<R: Request>(&self, request: impl Fn() -> R) -> Vec<R::Response> {
+        let Some(ep) = &self.ep else {
+            return vec![];
+        };
+        let calls = (self.peers.iter().enumerate())
+            .filter(|(i, _)| *i != self.me)
+            .map(|(_, addr)| ep.call_timeout(*addr, request(), TSO_HEARTBEAT_INTERVAL * 2));
+        futures::future::join_all(calls)
+            .await
+            .into_iter()
+            .filter_map(|rsp| rsp.ok())
+            .collect()
+    }
+
+    /// Becomes a follower of `term`.
+    async fn step_down(&self, state: &mut TsoState, term: u64, leader: Option<usize>) {
+        if term > state.meta.get().term {
+            let mut meta = state.meta.get().clone();
+            meta.term = term;
+            meta.voted_for = None;
+            if let Err(e) = state.meta.save(meta).await {
+                tracing::warn!("failed to persist tso meta: {e}");
+            }
+        }
+        if state.role == TsoRole::Leader {
+            tracing::info!(me = self.me, term, "tso step down");
+        }
+        state.role = TsoRole::Follower { leader };
+        state.lease_until = None;
+    }
+}
+
+fn election_timeout() -> Duration {
+    Duration::from_millis(madsim::rand::thread_rng().gen_range(TSO_ELECTION_TIMEOUT))
 }
 
 #[madsim::service]
 impl TimestampOracle {
-    // example get_timestamp RPC handler.
     #[rpc]
-    async fn get_timestamp(&self, _: TimestampRequest) -> TimestampResponse {
-        let ts = self.next_ts.fetch_add(1, Ordering::SeqCst);
-        TimestampResponse { ts }
+    async fn get_timestamp(&self, req: TimestampRequest) -> Result<TimestampResponse, TsoError> {
+        let count = req.count.max(1);
+        loop {
+            let mut state = self.state.lock().await;
+            if state.role != TsoRole::Leader {
+                return Err(self.not_leader(&state));
+            }
+            if !self.standalone() && state.lease_until.is_none_or(|t| t <= Instant::now()) {
+                return Err(TsoError::NotLeader { leader: None });
+            }
+            let (ts, window) = self.next_ts(&state);
+            let end = ts + count as u64;
+            if end > state.committed_limit {
+                // Reserve the next window before handing out anything beyond the mark.
+                drop(state);
+                if !self.replicate(end + window).await {
+                    return Err(TsoError::NotLeader { leader: None });
+                }
+                continue;
+            }
+            state.next_ts = end;
+            return Ok(TimestampResponse { ts, count });
+        }
     }
+
+    #[rpc]
+    async fn vote(&self, req: TsoVoteRequest) -> TsoVoteResponse {
+        let mut state = self.state.lock().await;
+        let now = Instant::now();
+        let term = state.meta.get().term;
+        let in_lease = match state.role {
+            TsoRole::Leader => state.lease_until.is_some_and(|t| t > now),
+            _ => now < state.last_heard + TSO_LEASE,
+        };
+        if req.term < term || in_lease {
+            return TsoVoteResponse {
+                term,
+                granted: false,
+                limit: 0,
+            };
+        }
+        if req.term > term {
+            self.step_down(&mut state, req.term, None).await;
+        }
+        let mut meta = state.meta.get().clone();
+        let granted = meta.voted_for.is_none_or(|c| c == req.candidate);
+        if granted {
+            meta.voted_for = Some(req.candidate);
+            if let Err(e) = state.meta.save(meta.clone()).await {
+                tracing::warn!("failed to persist tso meta: {e}");
+                return TsoVoteResponse {
+                    term: meta.term,
+                    granted: false,
+                    limit: 0,
+                };
+            }
+            state.last_heard = now;
+            state.election_deadline = now + election_timeout();
+        }
+        TsoVoteResponse {
+            term: meta.term,
+            granted,
+            limit: meta.limit,
+        }
+    }
+
+    #[rpc]
+    async fn append(&self, req: TsoAppendRequest) -> TsoAppendResponse {
+        let mut state = self.state.lock().await;
+        let term = state.meta.get().term;
+        if req.term < term {
+            return TsoAppendResponse {
+                term,
+                success: false,
+            };
+        }
+        if state.role
+            != (TsoRole::Follower {
+                leader: Some(req.leader),
+            })
+        {
+            self.step_down(&mut state, req.term, Some(req.leader)).await;
+        }
+        let mut meta = state.meta.get().clone();
+        if req.limit > meta.limit {
+            meta.limit = req.limit;
+            if let Err(e) = state.meta.save(meta).await {
+                tracing::warn!("failed to persist tso meta: {e}");
+                return TsoAppendResponse {
+                    term: req.term,
+                    success: false,
+                };
+            }
+        }
+        let now = Instant::now();
+        state.last_heard = now;
+        state.election_deadline = now + election_timeout();
+        TsoAppendResponse {
+            term: req.term,
+            success: true,
+        }
+    }
+}
+
+// MemoryStorage is used to wrap a Storage.
+// You may need to get a snapshot from it.
+//
+// A MemoryStorage runs either alone or as a member of a storage group, which
+// replicates the table through Raft. Every request is applied as a command
+// in the replicated log, so a new leader never loses a lock or a write.
+pub struct MemoryStorage<S = KvTable> {
+    raft: Arc<Raft<StorageState<S>>>,
+    // The regions being moved out by this node.
+    transferring: Arc<Mutex<BTreeSet<u64>>>,
+    // The counters of the lock collector.
+    lock_gc: Arc<Mutex<LockGcStats>>,
+    // The expired locks found by the last scan.
+    expired: Arc<Mutex<BTreeSet<LockId>>>,
+    // The addresses of all storage nodes, given by `with_storage_nodes`.
+    storage_nodes: Arc<Mutex<Vec<SocketAddr>>>,
+    // The routes to the storage nodes, set up once the lock collector needs them.
+    router: Arc<Mutex<Option<Arc<Router>>>>,
+}
+
+// The result of a batch request: an error for the whole batch, or the result of every key.
+type BatchResult<T, E> = Result<Vec<Result<T, E>>, E>;
+
+// A lock by its key and the start timestamp of its transaction.
+type LockId = (Vec<u8>, u64);
+
+struct StorageState<S> {
+    table: S,
+    // A copy of the metadata kept in the table.
+    meta: StorageMeta,
+    // Whether the commands are kept in a Raft log, which recovers the
+    // metadata changed by reads.
+    replicated: bool,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct StorageMeta {
+    // The regions owned by this node, by their start keys.
+    regions: BTreeMap<Vec<u8>, Region>,
+    // The regions moving out of this node.
+    outgoing: Vec<Transfer>,
+    // The latest version of each region installed from another node, as
+    // a transfer retries until it sees a response.
+    installed: BTreeMap<u64, u64>,
+    // The index of the last command applied to the table.
+    applied: u64,
+    // The highest timestamp carried by an applied command. A timestamp
+    // reaches the leader only after it is issued, and a transaction takes
+    // its commit timestamp after its prewrites succeed, so every write of
+    // a transaction committed below it is applied before.
+    safe_ts: u64,
 }
 
-// Key is a tuple (raw key, timestamp).
-pub type Key = (Vec<u8>, u64);
+/// A region moving to another storage node along with its records.
+#[derive(Clone, Serialize, Deserialize)]
+struct Transfer {
+    region: Region,
+    merge_into: Option<u64>,
+    target: SocketAddr,
+}
 
-#[derive(Clone, PartialEq, Eq)]
-pub enum Value {
-    Timestamp(u64),
-    Vector(Vec<u8>),
+/// A request applied to the table of every member of a storage group.
+#[derive(Serialize, Deserialize)]
+enum Command {
+    Get(GetRequest),
+    BatchGet(BatchGetRequest),
+    Scan(ScanRequest),
+    Prewrite(PrewriteRequest),
+    Commit(CommitRequest),
+    Check(CheckRequest),
+    CheckTxnStatus(CheckTxnStatusRequest),
+    TxnHeartBeat(TxnHeartBeatRequest),
+    CheckLocks(CheckLocksRequest),
+    Rollback(RollbackRequest),
+    BatchPrewrite(BatchPrewriteRequest),
+    OnePhaseCommit(OnePhaseCommitRequest),
+    BatchCommit(BatchCommitRequest),
+    BatchRollback(BatchRollbackRequest),
+    ResolveLock(ResolveLockRequest),
+    Split(SplitRequest),
+    Merge {
+        region: RegionEpoch,
+        into: Region,
+        target: SocketAddr,
+    },
+    InstallRegion(InstallRegionRequest),
+    // Erases the records of an outgoing region installed on its target.
+    FinishTransfer {
+        id: u64,
+    },
+    // Takes back an outgoing region its target refuses to install.
+    AbortTransfer {
+        id: u64,
+    },
 }
 
-impl Value {
-    fn as_bytes(&self) -> &[u8] {
+impl Command {
+    // Returns the latest timestamp carried by the command.
+    fn ts(&self) -> Option<u64> {
         match self {
-            Self::Vector(bytes) => bytes,
-            _ => panic!("expect vector"),
+            Command::Get(req) => Some(req.start_ts),
+            Command::BatchGet(req) => Some(req.start_ts),
+            Command::Scan(req) => Some(req.start_ts),
+            Command::Prewrite(req) => Some(req.start_ts),
+            Command::Commit(req) => Some(req.commit_ts),
+            Command::Check(req) => Some(req.lock_ts),
+            Command::CheckTxnStatus(req) => Some(req.lock_ts),
+            Command::TxnHeartBeat(req) => Some(req.start_ts),
+            Command::CheckLocks(req) => Some(req.start_ts),
+            Command::Rollback(req) => Some(req.start_ts),
+            Command::BatchPrewrite(req) => Some(req.start_ts),
+            Command::OnePhaseCommit(req) => Some(req.start_ts),
+            Command::BatchCommit(req) => Some(req.commit_ts),
+            Command::BatchRollback(req) => Some(req.start_ts),
+            Command::ResolveLock(req) => Some(req.commit_ts.unwrap_or(req.start_ts)),
+            // the target must not commit below the reads served by the source
+            Command::InstallRegion(req) => Some(req.safe_ts),
+            _ => None,
         }
     }
 
-    fn as_ts(&self) -> u64 {
-        match self {
-            Self::Timestamp(ts) => *ts,
-            _ => panic!("expect timestamp"),
-        }
+    // Returns whether the command only reads the table.
+    fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Command::Get(_)
+                | Command::BatchGet(_)
+                | Command::Scan(_)
+                | Command::Check(_)
+                | Command::CheckLocks(_)
+        )
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Write(Vec<u8>, Vec<u8>);
-
-pub enum Column {
-    Write,
-    Data,
-    Lock,
+impl<S> Clone for MemoryStorage<S> {
+    fn clone(&self) -> Self {
+        MemoryStorage {
+            raft: self.raft.clone(),
+            transferring: self.transferring.clone(),
+            lock_gc: self.lock_gc.clone(),
+            expired: self.expired.clone(),
+            storage_nodes: self.storage_nodes.clone(),
+            router: self.router.clone(),
+        }
+    }
 }
 
-// KvTable is used to simulate Google's Bigtable.
-// It provides three columns: Write, Data, and Lock.
-#[derive(Clone, Default)]
-pub struct KvTable {
-    write: BTreeMap<Key, Value>,
-    data: BTreeMap<Key, Value>,
-    lock: BTreeMap<Key, Value>,
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new(KvTable::default())
+    }
 }
 
-impl KvTable {
-    /// Reads the latest key-value record from a specified column
-    /// in MemoryStorage with a given key and a timestamp range.
-    #[inline]
-    fn read(
-        &self,
-        key: Vec<u8>,
-        column: Column,
-        ts_range: impl RangeBounds<u64>,
-    ) -> Option<(u64, &Value)> {
-        let map = match column {
-            Column::Write => &self.write,
-            Column::Data => &self.data,
-            Column::Lock => &self.lock,
-        };
-        let start = (
-            key.clone(),
-            match ts_range.start_bound() {
-                Bound::Included(ts) => *ts,
-                Bound::Excluded(ts) => *ts + 1,
-                Bound::Unbounded => 0,
-            },
-        );
-        let end = (
-            key,
-            match ts_range.end_bound() {
-                Bound::Included(ts) => *ts,
-                Bound::Excluded(ts) => *ts - 1,
-                Bound::Unbounded => u64::MAX,
-            },
-        );
-        map.range(start..=end)
-            .next_back()
-            .map(|((_, ts), v)| (*ts, v))
+impl<S: Storage> MemoryStorage<S> {
+    /// Creates a new MemoryStorage on top of `table`, owning the whole keyspace at first.
+    pub fn new(table: S) -> Self {
+        let region = Region {
+            id: 1,
+            start_key: vec![],
+            end_key: vec![],
+            version: 1,
+        };
+        Self::with_regions(table, vec![region])
     }
 
-    /// Writes a record to a specified column in MemoryStorage.
-    #[inline]
-    fn write(&mut self, key: Vec<u8>, column: Column, ts: u64, value: Value) {
-        let map = match column {
-            Column::Write => &mut self.write,
-            Column::Data => &mut self.data,
-            Column::Lock => &mut self.lock,
+    /// Creates a new MemoryStorage on top of `table`.
+    ///
+    /// If `table` is new, the storage owns `regions` at first. Otherwise it
+    /// keeps owning the regions recorded in `table`.
+    pub fn with_regions(table: S, regions: Vec<Region>) -> Self {
+        let state = StorageState::open(table, regions, false);
+        let outgoing = state.meta.outgoing.clone();
+        let storage = MemoryStorage {
+            raft: Arc::new(Raft::standalone(state)),
+            transferring: Default::default(),
+            lock_gc: Default::default(),
+            expired: Default::default(),
+            storage_nodes: Default::default(),
+            router: Default::default(),
         };
-        map.insert((key, ts), value);
+        // resume the transfers interrupted by a restart
+        for transfer in outgoing {
+            storage.start_transfer(transfer);
+        }
+        madsim::task::spawn(storage.clone().run_lock_gc());
+        storage
     }
 
-    /// Erases a record from a specified column in MemoryStorage.
-    #[inline]
-    fn erase(&mut self, key: Vec<u8>, column: Column, commit_ts: u64) {
-        let map = match column {
-            Column::Write => &mut self.write,
-            Column::Data => &mut self.data,
-            Column::Lock => &mut self.lock,
+    /// Opens the `me`-th member of a storage group with the given `peers`.
+    ///
+    /// The members replicate `table` through a log persisted under `dir`,
+    /// and own `regions` at first as in `with_regions`. `peers` contains the
+    /// addresses of all members, including this one. The member binds its own
+    /// address, so it must be served by `serve_group`.
+    pub async fn open_group(
+        table: S,
+        regions: Vec<Region>,
+        dir: impl AsRef<Path>,
+        peers: Vec<SocketAddr>,
+        me: usize,
+    ) -> io::Result<Self> {
+        if peers.len() <= 1 {
+            return Ok(Self::with_regions(table, regions));
+        }
+        let state = StorageState::open(table, regions, true);
+        let ep = Endpoint::bind(peers[me]).await?;
+        let storage = MemoryStorage {
+            raft: Raft::open_group(state, dir, peers, me, ep).await?,
+            transferring: Default::default(),
+            lock_gc: Default::default(),
+            expired: Default::default(),
+            storage_nodes: Default::default(),
+            router: Default::default(),
         };
-        map.remove(&(key, commit_ts));
+        madsim::task::spawn(storage.clone().run_transfers());
+        madsim::task::spawn(storage.clone().run_lock_gc());
+        Ok(storage)
     }
 
-    /// Finds the write record pointing to the specific timestamp.
-    /// Returns the commit timestamp.
-    #[inline]
-    fn find_write(&self, key: Vec<u8>, start_ts: u64) -> Option<u64> {
-        self.write
-            .range((key.clone(), 0)..=(key, u64::MAX))
-            .find(|(_, v)| v.as_ts() == start_ts)
-            .map(|((_, ts), _)| *ts)
+    /// Lets the lock collector check the transactions whose keys are owned
+    /// by the other storage nodes in `txn_addrs`, which include every member
+    /// of the storage groups.
+    ///
+    /// Otherwise the expired locks of such transactions are left for the
+    /// readers to resolve.
+    pub fn with_storage_nodes(self, txn_addrs: Vec<SocketAddr>) -> Self {
+        *self.storage_nodes.lock().unwrap() = txn_addrs;
+        self
     }
-}
 
-impl Display for KvTable {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut map = BTreeMap::<&[u8], BTreeMap<u64, (_, _, _)>>::new();
-        for ((key, ts), value) in &self.data {
-            map.entry(key).or_default().entry(*ts).or_default().0 = Some(value);
+    /// Serves a member of a storage group on its own address.
+    pub async fn serve_group(self) -> io::Result<()> {
+        match self.raft.endpoint().cloned() {
+            Some(ep) => self.serve_on(ep).await,
+            None => Err(io::Error::other("not a member of a storage group")),
         }
-        for ((key, ts), value) in &self.lock {
-            map.entry(key).or_default().entry(*ts).or_default().1 = Some(value);
+    }
+
+    /// Applies `command` through the storage group. Returns the leader as far
+    /// as this member knows if it is not the leader.
+    async fn propose<T: 'static>(&self, command: Command) -> Result<T, Option<SocketAddr>> {
+        let output = self.raft.propose(command).await.map_err(|e| e.leader)?;
+        Ok(*output.downcast().expect("unexpected output"))
+    }
+
+    /// Returns an endpoint to call other storage nodes.
+    async fn endpoint(&self) -> io::Result<Endpoint> {
+        match self.raft.endpoint() {
+            Some(ep) => Ok(ep.clone()),
+            None => Endpoint::bind("0.0.0.0:0").await,
         }
-        for ((key, ts), value) in &self.write {
-            map.entry(key).or_default().entry(*ts).or_default().2 = Some(value);
+    }
+
+    /// Keeps moving out the outgoing regions while leading the group, so that
+    /// a new leader takes over the transfers of the previous one.
+    async fn run_transfers(self) {
+        loop {
+            madsim::time::sleep(TRANSFER_TIMEOUT).await;
+            if !self.raft.is_leader().await {
+                continue;
+            }
+            let outgoing = self.raft.read(|state| state.meta.outgoing.clone()).await;
+            for transfer in outgoing {
+                self.start_transfer(transfer);
+            }
+        }
+    }
+
+    /// Keeps cleaning up the expired locks while leading the group, so that
+    /// the locks of crashed clients do not wait for a reader to run into them.
+    async fn run_lock_gc(self) {
+        loop {
+            madsim::time::sleep(LOCK_GC_INTERVAL).await;
+            if self.raft.is_leader().await {
+                self.collect_locks().await;
+            }
         }
+    }
 
-        let mut table = comfy_table::Table::new();
-        table.set_header(vec!["Key", "Data", "Lock", "Write"]);
-        for (key, map) in map {
-            let value_to_string = |ts: u64, v: Option<&Value>| match v {
-                Some(Value::Timestamp(t)) => format!("{ts}: data@{t}"),
-                Some(Value::Vector(v)) => format!("{ts}: {}", String::from_utf8_lossy(v)),
-                None => String::new(),
+    /// Resolves the expired locks on this node, checking their transactions
+    /// on the storage nodes owning the primary and secondary keys.
+    async fn collect_locks(&self) {
+        let current_time = physical_now();
+        let locks = self
+            .raft
+            .read(|state| state.expired_locks(current_time))
+            .await;
+        // a lock left in place is found again by the next scan, but counted once
+        let found = {
+            let keys = (locks.iter())
+                .map(|(_, key, start_ts, _)| (key.clone(), *start_ts))
+                .collect::<BTreeSet<_>>();
+            let mut expired = self.expired.lock().unwrap();
+            let found = keys.difference(&expired).count() as u64;
+            *expired = keys;
+            found
+        };
+        if locks.is_empty() {
+            return;
+        }
+        // the expired locks of every transaction: one of them, whether the
+        // primary key is among them, and their regions
+        let mut txns = BTreeMap::<u64, (Lock, bool, Vec<RegionEpoch>)>::new();
+        for (region, key, start_ts, lock) in locks {
+            let primary = key == lock.primary;
+            let txn = txns.entry(start_ts).or_insert((lock, false, vec![]));
+            txn.1 |= primary;
+            if !txn.2.contains(&region) {
+                txn.2.push(region);
+            }
+        }
+        let mut cleaned = 0;
+        for (start_ts, (lock, primary_expired, regions)) in txns {
+            // the primary key of an async-commit transaction is not
+            // prewritten after a secondary has expired
+            let req = |region| CheckTxnStatusRequest {
+                region,
+                primary_key: lock.primary.clone(),
+                lock_ts: start_ts,
+                current_time,
+                rollback_if_not_exist: true,
+            };
+            let status = self.call_owner(&lock.primary, req, Command::CheckTxnStatus);
+            let commit_ts = match status.await {
+                Some(Ok(TxnStatus::Committed(commit_ts))) => Some(commit_ts),
+                // the lock of the primary key goes with the rollback
+                Some(Ok(TxnStatus::RolledBack)) => {
+                    cleaned += primary_expired as u64;
+                    None
+                }
+                Some(Ok(TxnStatus::AsyncCommit {
+                    min_commit_ts,
+                    secondaries,
+                })) => {
+                    let status =
+                        self.check_secondaries(start_ts, min_commit_ts, secondaries, current_time);
+                    let Some(commit_ts) =
+                        (self.settle_primary(&lock.primary, start_ts, status.await)).await
+                    else {
+                        continue;
+                    };
+                    cleaned += primary_expired as u64;
+                    commit_ts
+                }
+                _ => continue,
             };
-            table.add_row(vec![
-                String::from_utf8_lossy(key).to_string(),
-                map.iter()
-                    .rev()
-                    .map(|(ts, (v, _, _))| value_to_string(*ts, *v))
-                    .join("\n"),
-                map.iter()
-                    .rev()
-                    .map(|(ts, (_, v, _))| value_to_string(*ts, *v))
-                    .join("\n"),
-                map.iter()
-                    .rev()
-                    .map(|(ts, (_, _, v))| value_to_string(*ts, *v))
-                    .join("\n"),
-            ]);
-        }
-        write!(f, "{table}")
+            for region in regions {
+                let command = Command::ResolveLock(ResolveLockRequest {
+                    region,
+                    start_ts,
+                    commit_ts,
+                    keys: vec![],
+                });
+                let resolved = self.propose::<Result<usize, ResolveError>>(command).await;
+                if let Ok(Ok(resolved)) = resolved {
+                    cleaned += resolved as u64;
+                }
+            }
+        }
+        tracing::info!(found, cleaned, "collect locks");
+        let mut stats = self.lock_gc.lock().unwrap();
+        stats.found += found;
+        stats.cleaned += cleaned;
     }
-}
 
-// MemoryStorage is used to wrap a KvTable.
-// You may need to get a snapshot from it.
-#[derive(Default, Clone)]
-pub struct MemoryStorage {
-    table: Arc<Mutex<KvTable>>,
+    /// Rebuilds the outcome of an expired async-commit transaction from the
+    /// locks of its secondaries like a reader.
+    ///
+    /// Returns `Uncommitted` unless the outcome is decided.
+    async fn check_secondaries(
+        &self,
+        start_ts: u64,
+        min_commit_ts: u64,
+        secondaries: Vec<Vec<u8>>,
+        current_time: u64,
+    ) -> TxnStatus {
+        let mut commit_ts = min_commit_ts;
+        for key in secondaries {
+            let req = |region| CheckLocksRequest {
+                region,
+                keys: vec![key.clone()],
+                start_ts,
+            };
+            let status = self.call_owner(&key, req, Command::CheckLocks);
+            match status
+                .await
+                .map(|status| status.map(|mut status| status.pop()))
+            {
+                Some(Ok(Some(Ok(LockStatus::Committed { commit_ts })))) => {
+                    return TxnStatus::Committed(commit_ts);
+                }
+                Some(Ok(Some(Ok(LockStatus::Locked { min_commit_ts })))) => {
+                    commit_ts = commit_ts.max(min_commit_ts);
+                }
+                Some(Ok(Some(Ok(LockStatus::Missing)))) => {
+                    // a secondary is never prewritten once rolled back
+                    let req = |region| CheckTxnStatusRequest {
+                        region,
+                        primary_key: key.clone(),
+                        lock_ts: start_ts,
+                        current_time,
+                        rollback_if_not_exist: true,
+                    };
+                    return match self.call_owner(&key, req, Command::CheckTxnStatus).await {
+                        Some(Ok(status @ (TxnStatus::Committed(_) | TxnStatus::RolledBack))) => {
+                            status
+                        }
+                        _ => TxnStatus::Uncommitted,
+                    };
+                }
+                _ => return TxnStatus::Uncommitted,
+            }
+        }
+        TxnStatus::Committed(commit_ts)
+    }
+
+    /// Commits or rolls back the primary key of the transaction started at
+    /// `start_ts` as `status` decides.
+    ///
+    /// Returns the outcome settled on the primary key, or `None` if it is not.
+    async fn settle_primary(
+        &self,
+        primary: &[u8],
+        start_ts: u64,
+        status: TxnStatus,
+    ) -> Option<Option<u64>> {
+        match status {
+            TxnStatus::Committed(commit_ts) => {
+                let req = |region| CommitRequest {
+                    region,
+                    is_primary: true,
+                    key: primary.to_vec(),
+                    start_ts,
+                    commit_ts,
+                };
+                let committed = self.call_owner(primary, req, Command::Commit).await;
+                committed
+                    .is_some_and(|r| r.is_ok())
+                    .then_some(Some(commit_ts))
+            }
+            TxnStatus::RolledBack => {
+                let req = |region| RollbackRequest {
+                    region,
+                    key: primary.to_vec(),
+                    start_ts,
+                };
+                match self.call_owner(primary, req, Command::Rollback).await {
+                    Some(Ok(())) => Some(None),
+                    Some(Err(RollbackError::AlreadyCommitted { commit_ts })) => {
+                        Some(Some(commit_ts))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Applies a request about `key` here if this node owns the key, or
+    /// sends it to the storage node owning it otherwise.
+    ///
+    /// Returns `None` if the request does not get through.
+    async fn call_owner<R, T, E>(
+        &self,
+        key: &[u8],
+        mut request: impl FnMut(RegionEpoch) -> R,
+        command: fn(R) -> Command,
+    ) -> Option<Result<T, E>>
+    where
+        R: Request<Response = Result<T, E>>,
+        T: 'static,
+        E: RouteError + 'static,
+    {
+        let region = (self.raft)
+            .read(|state| state.region_of(key).map(Region::epoch))
+            .await;
+        if let Some(region) = region {
+            return self.propose(command(request(region))).await.ok();
+        }
+        let router = self.router().await?;
+        router.call_region(key, request).await.ok()
+    }
+
+    /// Returns the router to the storage nodes, if they are known.
+    async fn router(&self) -> Option<Arc<Router>> {
+        if let Some(router) = self.router.lock().unwrap().clone() {
+            return Some(router);
+        }
+        let txn_addrs = self.storage_nodes.lock().unwrap().clone();
+        if txn_addrs.is_empty() {
+            return None;
+        }
+        let router = Arc::new(Router::new(self.endpoint().await.ok()?, txn_addrs));
+        Some(self.router.lock().unwrap().get_or_insert(router).clone())
+    }
+
+    /// Starts moving out a region unless it is already moving.
+    fn start_transfer(&self, transfer: Transfer) {
+        if self.transferring.lock().unwrap().insert(transfer.region.id) {
+            madsim::task::spawn(self.clone().transfer(transfer));
+        }
+    }
+
+    /// Starts moving out the outgoing region `id`, if there is one.
+    async fn start_outgoing(&self, id: u64) {
+        let transfer = (self.raft)
+            .read(|state| {
+                let mut outgoing = state.meta.outgoing.iter();
+                outgoing.find(|t| t.region.id == id).cloned()
+            })
+            .await;
+        if let Some(transfer) = transfer {
+            self.start_transfer(transfer);
+        }
+    }
+
+    /// Moves a region to its target storage node, then erases its records here.
+    async fn transfer(self, transfer: Transfer) {
+        let id = transfer.region.id;
+        self.install_remotely(transfer).await;
+        self.transferring.lock().unwrap().remove(&id);
+    }
+
+    async fn install_remotely(&self, transfer: Transfer) {
+        let ep = self.endpoint().await.expect("failed to bind endpoint");
+        let range = key_range(&transfer.region);
+        let (records, safe_ts) = (self.raft)
+            .read(|state| (state.records(range), state.meta.safe_ts))
+            .await;
+        let req = InstallRegionRequest {
+            region: transfer.region.clone(),
+            merge_into: transfer.merge_into,
+            records,
+            safe_ts,
+        };
+        let mut target = transfer.target;
+        let mut timeout = TRANSFER_TIMEOUT;
+        loop {
+            // Only the leader moves regions, and a new one starts over.
+            if !self.raft.is_leader().await {
+                return;
+            }
+            match ep.call_timeout(target, req.clone(), timeout).await {
+                Ok(Ok(())) => break,
+                Ok(Err(RegionError::NotLeader { leader })) => {
+                    target = leader.unwrap_or(target);
+                    continue;
+                }
+                // the region to merge into has changed, and it never changes back
+                Ok(Err(e)) => {
+                    tracing::error!(?e, id = req.region.id, "failed to install region");
+                    let command = Command::AbortTransfer { id: req.region.id };
+                    if self.propose::<()>(command).await.is_ok() {
+                        tracing::info!(id = req.region.id, "region transfer aborted");
+                    }
+                    return;
+                }
+                Err(e) => tracing::warn!(?e, id = req.region.id, "failed to transfer region"),
+            }
+            madsim::time::sleep(TRANSFER_TIMEOUT).await;
+            timeout = (timeout * 2).min(TRANSFER_MAX_TIMEOUT);
+        }
+        let command = Command::FinishTransfer { id: req.region.id };
+        if self.propose::<()>(command).await.is_ok() {
+            tracing::info!(id = req.region.id, %target, "region transferred");
+        }
+    }
 }
 
-#[madsim::service]
-impl MemoryStorage {
-    #[rpc]
+impl<S: Storage> StorageState<S> {
+    fn open(mut table: S, regions: Vec<Region>, replicated: bool) -> Self {
+        let meta = match table.meta() {
+            [] => {
+                let regions = regions
+                    .into_iter()
+                    .map(|region| (region.start_key.clone(), region))
+                    .collect();
+                let meta = StorageMeta {
+                    regions,
+                    ..StorageMeta::default()
+                };
+                table.set_meta(bincode::serialize(&meta).unwrap());
+                meta
+            }
+            meta => bincode::deserialize(meta).expect("corrupted storage metadata"),
+        };
+        StorageState {
+            table,
+            meta,
+            replicated,
+        }
+    }
+
+    /// Returns whether the storage owns `key` in the region of `epoch`.
+    fn owns(&self, key: &[u8], epoch: RegionEpoch) -> bool {
+        self.region_of(key)
+            .is_some_and(|region| region.epoch() == epoch)
+    }
+
+    /// Returns whether the storage owns `key` in any region.
+    fn owns_key(&self, key: &[u8]) -> bool {
+        self.region_of(key).is_some()
+    }
+
+    // Returns the locks expired by `current_time` with their regions, keys and timestamps.
+    fn expired_locks(&self, current_time: u64) -> Vec<(RegionEpoch, Vec<u8>, u64, Lock)> {
+        let mut locks = vec![];
+        for region in self.meta.regions.values() {
+            for ((key, ts), lock) in self.table.scan(Column::Lock, key_range(region)) {
+                let lock = lock.as_lock();
+                if lock.start_time.saturating_add(lock.ttl) <= current_time {
+                    locks.push((region.epoch(), key.clone(), *ts, lock.clone()));
+                }
+            }
+        }
+        locks
+    }
+
+    fn region_of(&self, key: &[u8]) -> Option<&Region> {
+        let range = (Bound::Unbounded, Bound::Included(key));
+        let (_, region) = self.meta.regions.range::<[u8], _>(range).next_back()?;
+        region.contains(key).then_some(region)
+    }
+
+    /// Returns the region of `epoch` if the storage owns it.
+    fn region(&self, epoch: RegionEpoch) -> Result<Region, RegionError> {
+        self.meta
+            .regions
+            .values()
+            .find(|region| region.epoch() == epoch)
+            .cloned()
+            .ok_or(RegionError::RegionChanged)
+    }
+
+    fn save_meta(&mut self) {
+        self.table.set_meta(bincode::serialize(&self.meta).unwrap());
+    }
+
+    /// Returns all records in `keys`.
+    fn records(&self, keys: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Vec<Record> {
+        let mut records = vec![];
+        for column in [Column::Write, Column::Data, Column::Lock] {
+            for ((key, ts), value) in self.table.scan(column, keys.clone()) {
+                records.push((column, key.clone(), *ts, value.clone()));
+            }
+        }
+        records
+    }
+
     fn get(&self, req: GetRequest) -> Result<Option<Vec<u8>>, GetError> {
-        let table = self.table.lock().unwrap();
-        if let Some((ts, primary)) = table.read(req.key.clone(), Column::Lock, ..=req.start_ts) {
-            let primary = primary.as_bytes().to_vec();
-            return Err(GetError::IsLocked { ts, primary });
+        if !self.owns(&req.key, req.region) {
+            return Err(GetError::RegionChanged);
+        }
+        (self.read_value(&req.key, req.start_ts)).map_err(|(ts, lock)| GetError::IsLocked {
+            ts,
+            primary: lock.primary.clone(),
+            async_commit: lock.async_commit,
+        })
+    }
+
+    fn batch_get(&self, req: BatchGetRequest) -> BatchResult<Option<Vec<u8>>, GetError> {
+        if !req.keys.iter().all(|key| self.owns(key, req.region)) {
+            return Err(GetError::RegionChanged);
+        }
+        let results = req.keys.iter().map(|key| {
+            (self.read_value(key, req.start_ts)).map_err(|(ts, lock)| GetError::IsLocked {
+                ts,
+                primary: lock.primary.clone(),
+                async_commit: lock.async_commit,
+            })
+        });
+        Ok(results.collect())
+    }
+
+    fn scan(&self, req: ScanRequest) -> Result<Vec<KvPair>, ScanError> {
+        let region = (self.region(req.region)).map_err(|_| ScanError::RegionChanged)?;
+        // the keys of the request within the region
+        let start_key = req.start_key.max(region.start_key.clone());
+        let end_key = match (req.end_key.is_empty(), region.end_key.is_empty()) {
+            (true, _) => region.end_key.clone(),
+            (false, true) => req.end_key,
+            (false, false) => req.end_key.min(region.end_key.clone()),
+        };
+        let range = key_range(&Region {
+            start_key,
+            end_key,
+            ..region
+        });
+        // a key may be locked before its first write
+        let writes = self.table.scan(Column::Write, range.clone());
+        let locks = self.table.scan(Column::Lock, range);
+        let keys: Box<dyn Iterator<Item = &Vec<u8>>> = match req.reverse {
+            false => Box::new(writes.map(|(k, _)| &k.0).merge(locks.map(|(k, _)| &k.0))),
+            true => Box::new(
+                (writes.rev().map(|(k, _)| &k.0))
+                    .merge_by(locks.rev().map(|(k, _)| &k.0), |a, b| a >= b),
+            ),
+        };
+        let mut pairs = vec![];
+        for key in keys.dedup() {
+            if pairs.len() >= req.limit {
+                break;
+            }
+            match self.read_value(key, req.start_ts) {
+                Ok(Some(value)) => pairs.push((key.clone(), value)),
+                Ok(None) => {}
+                Err((ts, lock)) => {
+                    return Err(ScanError::IsLocked {
+                        key: key.clone(),
+                        ts,
+                        primary: lock.primary.clone(),
+                        async_commit: lock.async_commit,
+                    })
+                }
+            }
+        }
+        Ok(pairs)
+    }
+
+    // Reads the latest value of `key` visible at `start_ts`, or returns the
+    // lock blocking the read with its timestamp.
+    fn read_value(&self, key: &[u8], start_ts: u64) -> Result<Option<Vec<u8>>, (u64, &Lock)> {
+        let table = &self.table;
+        if let Some((ts, lock)) = table.read(key.to_vec(), Column::Lock, ..=start_ts) {
+            let lock = lock.as_lock();
+            // an async-commit transaction commits above the timestamps read before its prewrite
+            if !lock.async_commit || lock.min_commit_ts <= start_ts {
+                return Err((ts, lock));
+            }
         }
-        let ts = match table.read(req.key.clone(), Column::Write, ..=req.start_ts) {
-            Some((_, v)) => v.as_ts(),
-            None => return Ok(None),
+        // skip the records leaving the value unchanged
+        let mut end = start_ts;
+        let ts = loop {
+            let Some((ts, v)) = table.read(key.to_vec(), Column::Write, ..=end) else {
+                return Ok(None);
+            };
+            match v.as_write().kind {
+                WriteKind::Put => break v.as_write().start_ts,
+                WriteKind::Delete => return Ok(None),
+                WriteKind::Lock | WriteKind::Rollback { .. } if ts > 0 => end = ts - 1,
+                WriteKind::Lock | WriteKind::Rollback { .. } => return Ok(None),
+            }
         };
         let value = table
-            .read(req.key, Column::Data, ts..=ts)
+            .read(key.to_vec(), Column::Data, ts..=ts)
             .unwrap()
             .1
             .as_bytes();
         Ok(Some(value.to_vec()))
     }
 
-    #[rpc]
-    fn prewrite(&self, req: PrewriteRequest) -> Result<(), PrewriteError> {
-        let mut table = self.table.lock().unwrap();
-        if let Some((ts, _)) = table.read(req.key.clone(), Column::Write, req.start_ts..) {
+    // Serves a read on a follower, which may lag behind the leader.
+    fn follower_get(&self, req: GetRequest) -> Result<Option<Vec<u8>>, GetError> {
+        let safe_ts = self.meta.safe_ts;
+        if req.start_ts > safe_ts {
+            return Err(GetError::DataNotReady { safe_ts });
+        }
+        self.get(req)
+    }
+
+    fn prewrite(&mut self, req: PrewriteRequest) -> Result<(), PrewriteError> {
+        if !self.owns(&req.key, req.region) {
+            return Err(PrewriteError::RegionChanged);
+        }
+        let lock = Lock {
+            primary: req.primary_key,
+            kind: WriteKind::Put,
+            async_commit: false,
+            secondaries: vec![],
+            min_commit_ts: 0,
+            ttl: req.lock_ttl,
+            start_time: req.start_time,
+        };
+        self.prewrite_key(req.key, Some(req.value), req.start_ts, lock)
+            .map(|_| ())
+    }
+
+    // Locks a key and returns the lowest commit timestamp of the lock.
+    fn prewrite_key(
+        &mut self,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+        start_ts: u64,
+        mut lock: Lock,
+    ) -> Result<u64, PrewriteError> {
+        if self.rolled_back(&key, start_ts) {
+            return Err(PrewriteError::AlreadyRolledBack);
+        }
+        if let Some(ts) = self.newer_write(&key, start_ts) {
             return Err(PrewriteError::WriteConflict { ts });
         }
-        if let Some((ts, _)) = table.read(req.key.clone(), Column::Lock, ..) {
+        let table = &mut self.table;
+        if let Some((ts, locked)) = table.read(key.clone(), Column::Lock, ..) {
+            // a retried request may find its own lock
+            if ts == start_ts {
+                return Ok(locked.as_lock().min_commit_ts);
+            }
             return Err(PrewriteError::IsLocked { ts });
         }
-        table.write(
-            req.key.clone(),
-            Column::Data,
-            req.start_ts,
-            Value::Vector(req.value),
-        );
-        table.write(
-            req.key.clone(),
-            Column::Lock,
-            req.start_ts,
-            Value::Vector(req.primary_key),
-        );
+        if lock.async_commit {
+            // every read served so far must not see the transaction,
+            // nor may it commit below the floor the client got from TSO
+            lock.min_commit_ts = lock.min_commit_ts.max(self.meta.safe_ts + 1);
+        }
+        let min_commit_ts = lock.min_commit_ts;
+        if let Some(value) = value {
+            table.write(key.clone(), Column::Data, start_ts, Value::Vector(value));
+        }
+        table.write(key, Column::Lock, start_ts, Value::Lock(lock));
         tracing::debug!("prewrite\n{}", table);
-        Ok(())
+        Ok(min_commit_ts)
     }
 
-    #[rpc]
-    fn commit(&self, req: CommitRequest) -> Result<(), CommitError> {
-        let mut table = self.table.lock().unwrap();
-        table.write(
+    fn commit(&mut self, req: CommitRequest) -> Result<(), CommitError> {
+        if !self.owns(&req.key, req.region) {
+            return Err(CommitError::RegionChanged);
+        }
+        // a retried request may find its own commit
+        if (self.table)
+            .find_write(req.key.clone(), req.start_ts)
+            .is_some()
+        {
+            return Ok(());
+        }
+        if self.rolled_back(&req.key, req.start_ts) {
+            return Err(CommitError::AlreadyRolledBack);
+        }
+        let kind = match self.table.read(req.key.clone(), Column::Lock, ..) {
+            Some((ts, lock)) if ts == req.start_ts => lock.as_lock().kind,
+            Some((ts, _)) => return Err(CommitError::TxnLockMismatch { ts }),
+            None => return Err(CommitError::LockNotFound),
+        };
+        self.write_commit(
             req.key.clone(),
-            Column::Write,
             req.commit_ts,
-            Value::Timestamp(req.start_ts),
+            Write::new(kind, req.start_ts),
         );
+        self.table.erase(req.key, Column::Lock, req.start_ts);
+        tracing::debug!("commit\n{}", self.table);
+        Ok(())
+    }
+
+    // Writes a commit record, keeping a protected rollback record at the same timestamp.
+    fn write_commit(&mut self, key: Vec<u8>, commit_ts: u64, mut write: Write) {
+        if let Some((_, v)) = self
+            .table
+            .read(key.clone(), Column::Write, commit_ts..=commit_ts)
+        {
+            write.overlapped_rollback =
+                v.as_write().kind == WriteKind::Rollback { protected: true };
+        }
+        self.table
+            .write(key, Column::Write, commit_ts, Value::Write(write));
+    }
+
+    // Whether the transaction starting at `start_ts` was rolled back on `key`.
+    fn rolled_back(&self, key: &[u8], start_ts: u64) -> bool {
+        match (self.table).read(key.to_vec(), Column::Write, start_ts..=start_ts) {
+            Some((_, v)) => v.as_write().is_rollback() || v.as_write().overlapped_rollback,
+            None => false,
+        }
+    }
+
+    // Returns the timestamp of the latest commit on `key` from `start_ts` on,
+    // which conflicts with a transaction starting at `start_ts`.
+    fn newer_write(&self, key: &[u8], start_ts: u64) -> Option<u64> {
+        (self.table.scan(Column::Write, key.to_vec()..=key.to_vec()))
+            .rev()
+            .take_while(|((_, ts), _)| *ts >= start_ts)
+            .find(|(_, v)| !v.as_write().is_rollback())
+            .map(|((_, ts), _)| *ts)
+    }
+
+    fn check(&self, req: CheckRequest) -> Result<TxnStatus, CheckError> {
+        if !self.owns(&req.key, req.region) {
+            return Err(CheckError::RegionChanged);
+        }
+        if let Some(commit_ts) = self.table.find_write(req.key.clone(), req.lock_ts) {
+            return Ok(TxnStatus::Committed(commit_ts));
+        }
+        let lock = self
+            .table
+            .read(req.key, Column::Lock, req.lock_ts..=req.lock_ts);
+        match lock.map(|(_, lock)| lock.as_lock()) {
+            Some(lock) if lock.async_commit => Ok(TxnStatus::AsyncCommit {
+                min_commit_ts: lock.min_commit_ts,
+                secondaries: lock.secondaries.clone(),
+            }),
+            _ => Ok(TxnStatus::Uncommitted),
+        }
+    }
+
+    fn check_txn_status(&mut self, req: CheckTxnStatusRequest) -> Result<TxnStatus, CheckError> {
+        if !self.owns(&req.primary_key, req.region) {
+            return Err(CheckError::RegionChanged);
+        }
+        let key = req.primary_key;
+        if let Some(commit_ts) = self.table.find_write(key.clone(), req.lock_ts) {
+            return Ok(TxnStatus::Committed(commit_ts));
+        }
+        if self.rolled_back(&key, req.lock_ts) {
+            return Ok(TxnStatus::RolledBack);
+        }
+        match (self.table).read(key.clone(), Column::Lock, req.lock_ts..=req.lock_ts) {
+            Some((_, lock)) => {
+                let lock = lock.as_lock();
+                if req.current_time < lock.start_time.saturating_add(lock.ttl) {
+                    return Ok(TxnStatus::Locked { ttl: lock.ttl });
+                }
+                // an async-commit transaction may be committed already
+                if lock.async_commit {
+                    return Ok(TxnStatus::AsyncCommit {
+                        min_commit_ts: lock.min_commit_ts,
+                        secondaries: lock.secondaries.clone(),
+                    });
+                }
+                let lock_ts = req.lock_ts;
+                tracing::debug!(key = ?String::from_utf8_lossy(&key), lock_ts, "lock expired");
+            }
+            None if !req.rollback_if_not_exist => return Ok(TxnStatus::Uncommitted),
+            // the primary key may be prewritten later, which the rollback record prevents
+            None => {}
+        }
+        // the transaction is neither committed nor rolled back, so the
+        // rollback writes a protected record on the primary key
+        let rollback = RollbackRequest {
+            region: req.region,
+            key,
+            start_ts: req.lock_ts,
+        };
+        self.rollback(rollback)
+            .map_err(|_| CheckError::RegionChanged)?;
+        Ok(TxnStatus::RolledBack)
+    }
+
+    fn txn_heart_beat(&mut self, req: TxnHeartBeatRequest) -> Result<u64, HeartBeatError> {
+        if !self.owns(&req.primary_key, req.region) {
+            return Err(HeartBeatError::RegionChanged);
+        }
+        let range = req.start_ts..=req.start_ts;
+        let Some((_, lock)) = (self.table).read(req.primary_key.clone(), Column::Lock, range)
+        else {
+            return Err(HeartBeatError::LockNotFound);
+        };
+        let mut lock = lock.as_lock().clone();
+        if lock.ttl < req.advise_ttl {
+            lock.ttl = req.advise_ttl;
+            let value = Value::Lock(lock.clone());
+            (self.table).write(req.primary_key, Column::Lock, req.start_ts, value);
+        }
+        Ok(lock.ttl)
+    }
+
+    fn check_locks(&self, req: CheckLocksRequest) -> BatchResult<LockStatus, CheckError> {
+        if !req.keys.iter().all(|key| self.owns(key, req.region)) {
+            return Err(CheckError::RegionChanged);
+        }
+        let status = |key: Vec<u8>| {
+            if let Some(commit_ts) = self.table.find_write(key.clone(), req.start_ts) {
+                return LockStatus::Committed { commit_ts };
+            }
+            match self
+                .table
+                .read(key, Column::Lock, req.start_ts..=req.start_ts)
+            {
+                Some((_, lock)) => LockStatus::Locked {
+                    min_commit_ts: lock.as_lock().min_commit_ts,
+                },
+                None => LockStatus::Missing,
+            }
+        };
+        Ok(req.keys.into_iter().map(|key| Ok(status(key))).collect())
+    }
+
+    fn rollback(&mut self, req: RollbackRequest) -> Result<(), RollbackError> {
+        if !self.owns(&req.key, req.region) {
+            return Err(RollbackError::RegionChanged);
+        }
+        let table = &mut self.table;
+        if let Some(commit_ts) = table.find_write(req.key.clone(), req.start_ts) {
+            return Err(RollbackError::AlreadyCommitted { commit_ts });
+        }
+        // only the lock of a secondary key tells the key is not the primary
+        let protected = match table.read(req.key.clone(), Column::Lock, req.start_ts..=req.start_ts)
+        {
+            Some((_, lock)) => lock.as_lock().primary == req.key,
+            None => true,
+        };
         table.erase(req.key.clone(), Column::Lock, req.start_ts);
-        tracing::debug!("commit\n{}", table);
+        table.erase(req.key.clone(), Column::Data, req.start_ts);
+        let rollback = Write::new(WriteKind::Rollback { protected }, req.start_ts);
+        match table.read(req.key.clone(), Column::Write, req.start_ts..=req.start_ts) {
+            // a commit record of another transaction takes the place
+            Some((_, v)) if !v.as_write().is_rollback() => {
+                if protected {
+                    let mut write = *v.as_write();
+                    write.overlapped_rollback = true;
+                    table.write(req.key, Column::Write, req.start_ts, Value::Write(write));
+                }
+            }
+            Some((_, v)) if v.as_write().kind == (WriteKind::Rollback { protected: true }) => {}
+            _ => table.write(req.key, Column::Write, req.start_ts, Value::Write(rollback)),
+        }
+        tracing::debug!("rollback\n{}", self.table);
         Ok(())
     }
 
+    fn batch_prewrite(&mut self, req: BatchPrewriteRequest) -> BatchResult<u64, PrewriteError> {
+        if !(req.mutations.iter()).all(|m| self.owns(&m.key, req.region)) {
+            return Err(PrewriteError::RegionChanged);
+        }
+        let results = req.mutations.into_iter().map(|m| {
+            let secondaries = match m.key == req.primary_key {
+                true => req.secondaries.clone(),
+                false => vec![],
+            };
+            let lock = Lock {
+                primary: req.primary_key.clone(),
+                kind: match m.value {
+                    Some(_) => WriteKind::Put,
+                    None => WriteKind::Delete,
+                },
+                async_commit: req.async_commit,
+                secondaries,
+                min_commit_ts: req.min_commit_ts,
+                ttl: req.lock_ttl,
+                start_time: req.start_time,
+            };
+            self.prewrite_key(m.key, m.value, req.start_ts, lock)
+        });
+        Ok(results.collect())
+    }
+
+    fn one_phase_commit(&mut self, req: OnePhaseCommitRequest) -> Result<u64, PrewriteError> {
+        for (region, mutations) in &req.regions {
+            if !mutations.iter().all(|m| self.owns(&m.key, *region)) {
+                return Err(PrewriteError::RegionChanged);
+            }
+        }
+        let mutations = req.regions.into_iter().flat_map(|(_, m)| m);
+        let mutations = mutations.collect::<Vec<_>>();
+        let table = &mut self.table;
+        // a retried request may find its own commit
+        if let Some(m) = mutations.first() {
+            if let Some(commit_ts) = table.find_write(m.key.clone(), req.start_ts) {
+                return Ok(commit_ts);
+            }
+        }
+        for m in &mutations {
+            if let Some(ts) = self.newer_write(&m.key, req.start_ts) {
+                return Err(PrewriteError::WriteConflict { ts });
+            }
+            if let Some((ts, _)) = self.table.read(m.key.clone(), Column::Lock, ..) {
+                return Err(PrewriteError::IsLocked { ts });
+            }
+        }
+        // every read served so far must not see the transaction,
+        // and every transaction begun after the commit must
+        let commit_ts = (self.meta.safe_ts + 1).max(req.min_commit_ts);
+        for m in mutations {
+            let kind = match m.value {
+                Some(value) => {
+                    let data = Value::Vector(value);
+                    (self.table).write(m.key.clone(), Column::Data, req.start_ts, data);
+                    WriteKind::Put
+                }
+                None => WriteKind::Delete,
+            };
+            self.write_commit(m.key, commit_ts, Write::new(kind, req.start_ts));
+        }
+        tracing::debug!("one-phase commit\n{}", self.table);
+        Ok(commit_ts)
+    }
+
+    fn batch_commit(&mut self, req: BatchCommitRequest) -> BatchResult<(), CommitError> {
+        if !req.keys.iter().all(|key| self.owns(key, req.region)) {
+            return Err(CommitError::RegionChanged);
+        }
+        let results = req.keys.into_iter().map(|key| {
+            self.commit(CommitRequest {
+                region: req.region,
+                is_primary: req.is_primary,
+                key,
+                start_ts: req.start_ts,
+                commit_ts: req.commit_ts,
+            })
+        });
+        Ok(results.collect())
+    }
+
+    fn batch_rollback(&mut self, req: BatchRollbackRequest) -> BatchResult<(), RollbackError> {
+        if !req.keys.iter().all(|key| self.owns(key, req.region)) {
+            return Err(RollbackError::RegionChanged);
+        }
+        let results = req.keys.into_iter().map(|key| {
+            self.rollback(RollbackRequest {
+                region: req.region,
+                key,
+                start_ts: req.start_ts,
+            })
+        });
+        Ok(results.collect())
+    }
+
+    fn resolve_lock(&mut self, req: ResolveLockRequest) -> Result<usize, ResolveError> {
+        let region = (self.region(req.region)).map_err(|_| ResolveError::RegionChanged)?;
+        if !req.keys.iter().all(|key| region.contains(key)) {
+            return Err(ResolveError::RegionChanged);
+        }
+        let keys = match req.keys.is_empty() {
+            true => (self.table.scan(Column::Lock, key_range(&region)))
+                .filter(|((_, ts), _)| *ts == req.start_ts)
+                .map(|((key, _), _)| key.clone())
+                .collect::<Vec<_>>(),
+            false => (req.keys.into_iter())
+                .filter(|key| {
+                    let range = req.start_ts..=req.start_ts;
+                    self.table.read(key.clone(), Column::Lock, range).is_some()
+                })
+                .collect(),
+        };
+        for key in &keys {
+            let (region, key, start_ts) = (req.region, key.clone(), req.start_ts);
+            let resolved = match req.commit_ts {
+                Some(commit_ts) => (self.commit(CommitRequest {
+                    region,
+                    is_primary: false,
+                    key,
+                    start_ts,
+                    commit_ts,
+                }))
+                .is_ok(),
+                None => (self.rollback(RollbackRequest {
+                    region,
+                    key,
+                    start_ts,
+                }))
+                .is_ok(),
+            };
+            // the keys are locked by the transaction, so neither can fail
+            debug_assert!(resolved, "failed to resolve lock");
+        }
+        Ok(keys.len())
+    }
+
+    fn split(&mut self, req: SplitRequest) -> Result<(), RegionError> {
+        let region = self.region(req.region)?;
+        if !region.contains(&req.split_key) || req.split_key == region.start_key {
+            return Err(RegionError::InvalidKey);
+        }
+        let left = Region {
+            end_key: req.split_key.clone(),
+            version: region.version + 1,
+            ..region.clone()
+        };
+        let right = Region {
+            id: req.new_region_id,
+            start_key: req.split_key,
+            end_key: region.end_key,
+            version: region.version + 1,
+        };
+        tracing::info!(?left, ?right, target = ?req.target, "split region");
+        self.meta.regions.insert(left.start_key.clone(), left);
+        match req.target {
+            Some(target) => self.meta.outgoing.push(Transfer {
+                region: right,
+                merge_into: None,
+                target,
+            }),
+            None => {
+                self.meta.regions.insert(right.start_key.clone(), right);
+            }
+        }
+        Ok(())
+    }
+
+    fn merge(
+        &mut self,
+        epoch: RegionEpoch,
+        into: Region,
+        target: SocketAddr,
+    ) -> Result<(), RegionError> {
+        let region = self.region(epoch)?;
+        if !is_adjacent(&into, &region) && !is_adjacent(&region, &into) {
+            return Err(RegionError::NotAdjacent);
+        }
+        tracing::info!(?region, ?into, %target, "merge region");
+        self.meta.regions.remove(&region.start_key);
+        self.meta.outgoing.push(Transfer {
+            region,
+            merge_into: Some(into.id),
+            target,
+        });
+        Ok(())
+    }
+
+    fn install_region(&mut self, req: InstallRegionRequest) -> Result<(), RegionError> {
+        // The previous request has installed it, and the region may have
+        // changed since then.
+        if self.meta.installed.get(&req.region.id) >= Some(&req.region.version) {
+            return Ok(());
+        }
+        let source = req.region.epoch();
+        let region = match req.merge_into {
+            None => req.region,
+            Some(id) => {
+                let into = self
+                    .meta
+                    .regions
+                    .values()
+                    .find(|r| r.id == id)
+                    .cloned()
+                    .ok_or(RegionError::RegionChanged)?;
+                let (start_key, end_key) = if is_adjacent(&into, &req.region) {
+                    (into.start_key.clone(), req.region.end_key)
+                } else if is_adjacent(&req.region, &into) {
+                    (req.region.start_key, into.end_key.clone())
+                } else {
+                    return Err(RegionError::NotAdjacent);
+                };
+                self.meta.regions.remove(&into.start_key);
+                Region {
+                    id,
+                    start_key,
+                    end_key,
+                    version: into.version.max(req.region.version) + 1,
+                }
+            }
+        };
+        tracing::info!(?region, records = req.records.len(), "install region");
+        for (column, key, ts, value) in req.records {
+            self.table.write(key, column, ts, value);
+        }
+        self.meta.regions.insert(region.start_key.clone(), region);
+        self.meta.installed.insert(source.id, source.version);
+        Ok(())
+    }
+
+    fn finish_transfer(&mut self, id: u64) {
+        let Some(i) = self.meta.outgoing.iter().position(|t| t.region.id == id) else {
+            return;
+        };
+        let transfer = self.meta.outgoing.remove(i);
+        for (column, key, ts, _) in self.records(key_range(&transfer.region)) {
+            // the keys may have moved back, e.g. when merging into a region on this node
+            if !self.owns_key(&key) {
+                self.table.erase(key, column, ts);
+            }
+        }
+    }
+
+    fn abort_transfer(&mut self, id: u64) {
+        let Some(i) = self.meta.outgoing.iter().position(|t| t.region.id == id) else {
+            return;
+        };
+        let transfer = self.meta.outgoing.remove(i);
+        // the records are still here, as the target has not installed them
+        let region = Region {
+            version: transfer.region.version + 1,
+            ..transfer.region
+        };
+        tracing::info!(?region, "take back region");
+        self.meta.regions.insert(region.start_key.clone(), region);
+    }
+}
+
+type Record = (Column, Vec<u8>, u64, Value);
+
+impl<S: Storage> StateMachine for StorageState<S> {
+    type Command = Command;
+
+    const DURABLE: bool = S::DURABLE;
+
+    fn apply(&mut self, index: u64, command: Option<Command>) -> Box<dyn Any + Send> {
+        let read_only = command.as_ref().is_none_or(Command::is_read_only);
+        let safe_ts = self.meta.safe_ts;
+        if let Some(ts) = command.as_ref().and_then(Command::ts) {
+            self.meta.safe_ts = self.meta.safe_ts.max(ts);
+        }
+        let output: Box<dyn Any + Send> = match command {
+            None => Box::new(()),
+            Some(Command::Get(req)) => Box::new(self.get(req)),
+            Some(Command::BatchGet(req)) => Box::new(self.batch_get(req)),
+            Some(Command::Scan(req)) => Box::new(self.scan(req)),
+            Some(Command::Prewrite(req)) => Box::new(self.prewrite(req)),
+            Some(Command::Commit(req)) => Box::new(self.commit(req)),
+            Some(Command::Check(req)) => Box::new(self.check(req)),
+            Some(Command::CheckTxnStatus(req)) => Box::new(self.check_txn_status(req)),
+            Some(Command::TxnHeartBeat(req)) => Box::new(self.txn_heart_beat(req)),
+            Some(Command::CheckLocks(req)) => Box::new(self.check_locks(req)),
+            Some(Command::Rollback(req)) => Box::new(self.rollback(req)),
+            Some(Command::BatchPrewrite(req)) => Box::new(self.batch_prewrite(req)),
+            Some(Command::OnePhaseCommit(req)) => Box::new(self.one_phase_commit(req)),
+            Some(Command::BatchCommit(req)) => Box::new(self.batch_commit(req)),
+            Some(Command::BatchRollback(req)) => Box::new(self.batch_rollback(req)),
+            Some(Command::ResolveLock(req)) => Box::new(self.resolve_lock(req)),
+            Some(Command::Split(req)) => Box::new(self.split(req)),
+            Some(Command::Merge {
+                region,
+                into,
+                target,
+            }) => Box::new(self.merge(region, into, target)),
+            Some(Command::InstallRegion(req)) => Box::new(self.install_region(req)),
+            Some(Command::FinishTransfer { id }) => {
+                self.finish_transfer(id);
+                Box::new(())
+            }
+            Some(Command::AbortTransfer { id }) => {
+                self.abort_transfer(id);
+                Box::new(())
+            }
+        };
+        self.meta.applied = index;
+        // a read changes the applied index and the safe timestamp at most,
+        // which the log recovers by applying it again
+        if !read_only || (!self.replicated && self.meta.safe_ts > safe_ts) {
+            self.save_meta();
+        }
+        output
+    }
+
+    fn applied(&self) -> u64 {
+        self.meta.applied
+    }
+
+    fn checkpoint(&mut self) {
+        self.save_meta();
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        let records = self.records((Bound::Unbounded, Bound::Unbounded));
+        bincode::serialize(&(&self.meta, records)).unwrap()
+    }
+
+    fn restore(&mut self, index: u64, snapshot: &[u8]) {
+        let (meta, records): (StorageMeta, Vec<Record>) =
+            bincode::deserialize(snapshot).expect("corrupted snapshot");
+        for (column, key, ts, _) in self.records((Bound::Unbounded, Bound::Unbounded)) {
+            self.table.erase(key, column, ts);
+        }
+        for (column, key, ts, value) in records {
+            self.table.write(key, column, ts, value);
+        }
+        self.meta = meta;
+        self.meta.applied = index;
+        self.save_meta();
+    }
+
+    fn sync(&mut self) -> impl Future<Output = io::Result<()>> + Send + 'static {
+        self.table.sync()
+    }
+}
+
+/// Returns the range of keys in `region`.
+fn key_range(region: &Region) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let end = match region.end_key.is_empty() {
+        true => Bound::Unbounded,
+        false => Bound::Excluded(region.end_key.clone()),
+    };
+    (Bound::Included(region.start_key.clone()), end)
+}
+
+/// Returns whether `a` ends right where `b` starts.
+fn is_adjacent(a: &Region, b: &Region) -> bool {
+    !a.end_key.is_empty() && a.end_key == b.start_key
+}
+
+#[madsim::service]
+impl<S: Storage> MemoryStorage<S> {
+    #[rpc]
+    async fn get(&self, req: GetRequest) -> Result<Option<Vec<u8>>, GetError> {
+        if req.follower_read && !self.raft.is_leader().await {
+            return self.raft.read(|state| state.follower_get(req)).await;
+        }
+        (self.propose(Command::Get(req)).await)
+            .unwrap_or_else(|leader| Err(GetError::NotLeader { leader }))
+    }
+
+    #[rpc]
+    async fn batch_get(&self, req: BatchGetRequest) -> BatchResult<Option<Vec<u8>>, GetError> {
+        (self.propose(Command::BatchGet(req)).await)
+            .unwrap_or_else(|leader| Err(GetError::NotLeader { leader }))
+    }
+
+    #[rpc]
+    async fn scan(&self, req: ScanRequest) -> Result<Vec<KvPair>, ScanError> {
+        (self.propose(Command::Scan(req)).await)
+            .unwrap_or_else(|leader| Err(ScanError::NotLeader { leader }))
+    }
+
+    #[rpc]
+    async fn prewrite(&self, req: PrewriteRequest) -> Result<(), PrewriteError> {
+        (self.propose(Command::Prewrite(req)).await)
+            .unwrap_or_else(|leader| Err(PrewriteError::NotLeader { leader }))
+    }
+
+    #[rpc]
+    async fn commit(&self, req: CommitRequest) -> Result<(), CommitError> {
+        (self.propose(Command::Commit(req)).await)
+            .unwrap_or_else(|leader| Err(CommitError::NotLeader { leader }))
+    }
+
+    #[rpc]
+    async fn check(&self, req: CheckRequest) -> Result<TxnStatus, CheckError> {
+        (self.propose(Command::Check(req)).await)
+            .unwrap_or_else(|leader| Err(CheckError::NotLeader { leader }))
+    }
+
     #[rpc]
-    fn check(&self, req: CheckRequest) -> Option<u64> {
-        let table = self.table.lock().unwrap();
-        table.find_write(req.key, req.lock_ts)
+    async fn check_txn_status(&self, req: CheckTxnStatusRequest) -> Result<TxnStatus, CheckError> {
+        (self.propose(Command::CheckTxnStatus(req)).await)
+            .unwrap_or_else(|leader| Err(CheckError::NotLeader { leader }))
     }
 
     #[rpc]
-    fn rollback(&self, req: RollbackRequest) -> Result<(), RollbackError> {
-        let mut table = self.table.lock().unwrap();
-        table.erase(req.key, Column::Lock, req.start_ts);
-        tracing::debug!("rollback\n{}", table);
+    async fn txn_heart_beat(&self, req: TxnHeartBeatRequest) -> Result<u64, HeartBeatError> {
+        (self.propose(Command::TxnHeartBeat(req)).await)
+            .unwrap_or_else(|leader| Err(HeartBeatError::NotLeader { leader }))
+    }
+
+    #[rpc]
+    async fn check_locks(&self, req: CheckLocksRequest) -> BatchResult<LockStatus, CheckError> {
+        (self.propose(Command::CheckLocks(req)).await)
+            .unwrap_or_else(|leader| Err(CheckError::NotLeader { leader }))
+    }
+
+    #[rpc]
+    async fn rollback(&self, req: RollbackRequest) -> Result<(), RollbackError> {
+        (self.propose(Command::Rollback(req)).await)
+            .unwrap_or_else(|leader| Err(RollbackError::NotLeader { leader }))
+    }
+
+    #[rpc]
+    async fn batch_prewrite(&self, req: BatchPrewriteRequest) -> BatchResult<u64, PrewriteError> {
+        (self.propose(Command::BatchPrewrite(req)).await)
+            .unwrap_or_else(|leader| Err(PrewriteError::NotLeader { leader }))
+    }
+
+    #[rpc]
+    async fn one_phase_commit(&self, req: OnePhaseCommitRequest) -> Result<u64, PrewriteError> {
+        (self.propose(Command::OnePhaseCommit(req)).await)
+            .unwrap_or_else(|leader| Err(PrewriteError::NotLeader { leader }))
+    }
+
+    #[rpc]
+    async fn batch_commit(&self, req: BatchCommitRequest) -> BatchResult<(), CommitError> {
+        (self.propose(Command::BatchCommit(req)).await)
+            .unwrap_or_else(|leader| Err(CommitError::NotLeader { leader }))
+    }
+
+    #[rpc]
+    async fn batch_rollback(&self, req: BatchRollbackRequest) -> BatchResult<(), RollbackError> {
+        (self.propose(Command::BatchRollback(req)).await)
+            .unwrap_or_else(|leader| Err(RollbackError::NotLeader { leader }))
+    }
+
+    #[rpc]
+    async fn resolve_lock(&self, req: ResolveLockRequest) -> Result<usize, ResolveError> {
+        (self.propose(Command::ResolveLock(req)).await)
+            .unwrap_or_else(|leader| Err(ResolveError::NotLeader { leader }))
+    }
+
+    #[rpc]
+    async fn lock_gc_stats(&self, _: LockGcStatsRequest) -> LockGcStats {
+        *self.lock_gc.lock().unwrap()
+    }
+
+    #[rpc]
+    async fn regions(&self, _: RegionsRequest) -> RegionsResponse {
+        let regions = (self.raft)
+            .read(|state| state.meta.regions.values().cloned().collect())
+            .await;
+        RegionsResponse {
+            peers: self.raft.peers().to_vec(),
+            leader: self.raft.leader().await,
+            regions,
+        }
+    }
+
+    #[rpc]
+    async fn split(&self, req: SplitRequest) -> Result<(), RegionError> {
+        let id = req.new_region_id;
+        (self.propose(Command::Split(req)).await)
+            .unwrap_or_else(|leader| Err(RegionError::NotLeader { leader }))?;
+        self.start_outgoing(id).await;
+        Ok(())
+    }
+
+    #[rpc]
+    async fn merge(&self, req: MergeRequest) -> Result<(), RegionError> {
+        if !self.raft.is_leader().await {
+            let leader = self.raft.leader().await;
+            return Err(RegionError::NotLeader { leader });
+        }
+        // Check the target first, rather than giving up the region until it is refused.
+        let ep = self
+            .endpoint()
+            .await
+            .map_err(|_| RegionError::Unreachable)?;
+        let rsp = ep
+            .call_timeout(req.target, RegionsRequest {}, TRANSFER_TIMEOUT)
+            .await
+            .map_err(|_| RegionError::Unreachable)?;
+        let into = (rsp.regions.into_iter())
+            .find(|region| region.epoch() == req.into)
+            .ok_or(RegionError::RegionChanged)?;
+        let command = Command::Merge {
+            region: req.region,
+            into,
+            target: req.target,
+        };
+        (self.propose(command).await)
+            .unwrap_or_else(|leader| Err(RegionError::NotLeader { leader }))?;
+        self.start_outgoing(req.region.id).await;
         Ok(())
     }
+
+    #[rpc]
+    async fn install_region(&self, req: InstallRegionRequest) -> Result<(), RegionError> {
+        (self.propose(Command::InstallRegion(req)).await)
+            .unwrap_or_else(|leader| Err(RegionError::NotLeader { leader }))
+    }
+
+    #[rpc]
+    async fn raft_vote(&self, req: RaftVoteRequest) -> RaftVoteResponse {
+        self.raft.vote(req).await
+    }
+
+    #[rpc]
+    async fn raft_append(&self, req: RaftAppendRequest) -> RaftAppendResponse {
+        self.raft.append(req).await
+    }
+
+    #[rpc]
+    async fn raft_snapshot(&self, req: RaftSnapshotRequest) -> RaftSnapshotResponse {
+        self.raft.install_snapshot(req).await
+    }
 }
@@ -0,0 +1,466 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::Arc;
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::wal::Wal;
+
+// LOG_SIZE_LIMIT is the size of the write-ahead log that triggers a new snapshot.
+const LOG_SIZE_LIMIT: u64 = 1 << 20;
+
+// Key is a tuple (raw key, timestamp).
+pub type Key = (Vec<u8>, u64);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Value {
+    Vector(Vec<u8>),
+    Lock(Lock),
+    Write(Write),
+}
+
+/// The record of a finished transaction in the Write column.
+///
+/// A committed transaction leaves a record at its commit timestamp, and a
+/// rolled-back one leaves a rollback record at its start timestamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Write {
+    pub kind: WriteKind,
+    /// The start timestamp of the transaction, which its data is written at.
+    pub start_ts: u64,
+    /// Whether the transaction starting at the timestamp of this record was
+    /// rolled back too, as its protected rollback record would take the same place.
+    pub overlapped_rollback: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WriteKind {
+    Put,
+    /// A tombstone hiding the older versions of the key.
+    Delete,
+    /// A key locked by the transaction but left unchanged.
+    Lock,
+    /// The transaction was rolled back, so its prewrites and commits must fail.
+    ///
+    /// A protected rollback is kept even if a commit record of another
+    /// transaction lands at the same timestamp. The rollback of the primary
+    /// key decides the outcome of the transaction, so it is always protected.
+    Rollback {
+        protected: bool,
+    },
+}
+
+impl Write {
+    pub fn new(kind: WriteKind, start_ts: u64) -> Self {
+        Write {
+            kind,
+            start_ts,
+            overlapped_rollback: false,
+        }
+    }
+
+    pub fn is_rollback(&self) -> bool {
+        matches!(self.kind, WriteKind::Rollback { .. })
+    }
+}
+
+/// The lock left on a key by a prewrite.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lock {
+    pub primary: Vec<u8>,
+    /// The kind of the write record the lock turns into once committed.
+    pub kind: WriteKind,
+    /// Whether the transaction counts as committed once all of its keys are prewritten.
+    pub async_commit: bool,
+    /// The other keys of an async-commit transaction, kept in the lock of its primary key.
+    pub secondaries: Vec<Vec<u8>>,
+    /// The lowest timestamp an async-commit transaction may commit at.
+    pub min_commit_ts: u64,
+    /// How long the lock lives in milliseconds, counted from `start_time`.
+    pub ttl: u64,
+    /// The physical time the transaction started at, in milliseconds since the Unix epoch.
+    pub start_time: u64,
+}
+
+impl Value {
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::Vector(bytes) => bytes,
+            _ => panic!("expect vector"),
+        }
+    }
+
+    pub(crate) fn as_write(&self) -> &Write {
+        match self {
+            Self::Write(write) => write,
+            _ => panic!("expect write"),
+        }
+    }
+
+    pub(crate) fn as_lock(&self) -> &Lock {
+        match self {
+            Self::Lock(lock) => lock,
+            _ => panic!("expect lock"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Column {
+    Write,
+    Data,
+    Lock,
+}
+
+/// Storage is the multi-column table behind a `MemoryStorage`.
+pub trait Storage: Display + Send + 'static {
+    /// Whether the records survive a restart once synced.
+    const DURABLE: bool;
+
+    /// Reads the latest key-value record from a specified column
+    /// with a given key and a timestamp range.
+    fn read(
+        &self,
+        key: Vec<u8>,
+        column: Column,
+        ts_range: impl RangeBounds<u64>,
+    ) -> Option<(u64, &Value)>;
+
+    /// Writes a record to a specified column.
+    fn write(&mut self, key: Vec<u8>, column: Column, ts: u64, value: Value);
+
+    /// Erases a record from a specified column.
+    fn erase(&mut self, key: Vec<u8>, column: Column, commit_ts: u64);
+
+    /// Finds the commit record pointing to the specific timestamp.
+    /// Returns the commit timestamp.
+    fn find_write(&self, key: Vec<u8>, start_ts: u64) -> Option<u64>;
+
+    /// Iterates over the records of a specified column whose raw keys fall in `keys`.
+    fn scan(
+        &self,
+        column: Column,
+        keys: impl RangeBounds<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = (&Key, &Value)>;
+
+    /// Returns the metadata last set by `set_meta`, which is empty at first.
+    fn meta(&self) -> &[u8];
+
+    /// Replaces the metadata. It becomes durable along with the records.
+    fn set_meta(&mut self, meta: Vec<u8>);
+
+    /// Returns a future that resolves once all previous writes are durable.
+    ///
+    /// The future does not borrow the storage, so it can be awaited after
+    /// releasing the storage, letting concurrent requests share one sync.
+    fn sync(&mut self) -> impl Future<Output = io::Result<()>> + Send + 'static;
+}
+
+// KvTable is used to simulate Google's Bigtable.
+// It provides three columns: Write, Data, and Lock.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct KvTable {
+    write: BTreeMap<Key, Value>,
+    data: BTreeMap<Key, Value>,
+    lock: BTreeMap<Key, Value>,
+    meta: Vec<u8>,
+}
+
+impl Storage for KvTable {
+    const DURABLE: bool = false;
+
+    #[inline]
+    fn read(
+        &self,
+        key: Vec<u8>,
+        column: Column,
+        ts_range: impl RangeBounds<u64>,
+    ) -> Option<(u64, &Value)> {
+        let map = match column {
+            Column::Write => &self.write,
+            Column::Data => &self.data,
+            Column::Lock => &self.lock,
+        };
+        let start = (
+            key.clone(),
+            match ts_range.start_bound() {
+                Bound::Included(ts) => *ts,
+                Bound::Excluded(ts) => *ts + 1,
+                Bound::Unbounded => 0,
+            },
+        );
+        let end = (
+            key,
+            match ts_range.end_bound() {
+                Bound::Included(ts) => *ts,
+                Bound::Excluded(ts) => *ts - 1,
+                Bound::Unbounded => u64::MAX,
+            },
+        );
+        map.range(start..=end)
+            .next_back()
+            .map(|((_, ts), v)| (*ts, v))
+    }
+
+    #[inline]
+    fn write(&mut self, key: Vec<u8>, column: Column, ts: u64, value: Value) {
+        let map = match column {
+            Column::Write => &mut self.write,
+            Column::Data => &mut self.data,
+            Column::Lock => &mut self.lock,
+        };
+        map.insert((key, ts), value);
+    }
+
+    #[inline]
+    fn erase(&mut self, key: Vec<u8>, column: Column, commit_ts: u64) {
+        let map = match column {
+            Column::Write => &mut self.write,
+            Column::Data => &mut self.data,
+            Column::Lock => &mut self.lock,
+        };
+        map.remove(&(key, commit_ts));
+    }
+
+    #[inline]
+    fn find_write(&self, key: Vec<u8>, start_ts: u64) -> Option<u64> {
+        self.write
+            .range((key.clone(), 0)..=(key, u64::MAX))
+            .find(|(_, v)| v.as_write().start_ts == start_ts && !v.as_write().is_rollback())
+            .map(|((_, ts), _)| *ts)
+    }
+
+    fn scan(
+        &self,
+        column: Column,
+        keys: impl RangeBounds<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = (&Key, &Value)> {
+        let map = match column {
+            Column::Write => &self.write,
+            Column::Data => &self.data,
+            Column::Lock => &self.lock,
+        };
+        let start = match keys.start_bound() {
+            Bound::Included(key) => Bound::Included((key.clone(), 0)),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), u64::MAX)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match keys.end_bound() {
+            Bound::Included(key) => Bound::Included((key.clone(), u64::MAX)),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), 0)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        map.range((start, end))
+    }
+
+    fn meta(&self) -> &[u8] {
+        &self.meta
+    }
+
+    fn set_meta(&mut self, meta: Vec<u8>) {
+        self.meta = meta;
+    }
+
+    fn sync(&mut self) -> impl Future<Output = io::Result<()>> + Send + 'static {
+        std::future::ready(Ok(()))
+    }
+}
+
+impl Display for KvTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut map = BTreeMap::<&[u8], BTreeMap<u64, (_, _, _)>>::new();
+        for ((key, ts), value) in &self.data {
+            map.entry(key).or_default().entry(*ts).or_default().0 = Some(value);
+        }
+        for ((key, ts), value) in &self.lock {
+            map.entry(key).or_default().entry(*ts).or_default().1 = Some(value);
+        }
+        for ((key, ts), value) in &self.write {
+            map.entry(key).or_default().entry(*ts).or_default().2 = Some(value);
+        }
+
+        let mut table = comfy_table::Table::new();
+        table.set_header(vec!["Key", "Data", "Lock", "Write"]);
+        for (key, map) in map {
+            let value_to_string = |ts: u64, v: Option<&Value>| match v {
+                Some(Value::Write(write)) => {
+                    let kind = match write.kind {
+                        WriteKind::Put => "data",
+                        WriteKind::Delete => "delete",
+                        WriteKind::Lock => "lock",
+                        WriteKind::Rollback { protected: true } => "rollback (protected)",
+                        WriteKind::Rollback { protected: false } => "rollback",
+                    };
+                    match write.overlapped_rollback {
+                        true => format!("{ts}: {kind}@{} (rollback@{ts})", write.start_ts),
+                        false => format!("{ts}: {kind}@{}", write.start_ts),
+                    }
+                }
+                Some(Value::Vector(v)) => format!("{ts}: {}", String::from_utf8_lossy(v)),
+                Some(Value::Lock(lock)) => match lock.async_commit {
+                    true => format!(
+                        "{ts}: {} (async@{})",
+                        String::from_utf8_lossy(&lock.primary),
+                        lock.min_commit_ts
+                    ),
+                    false => format!("{ts}: {}", String::from_utf8_lossy(&lock.primary)),
+                },
+                None => String::new(),
+            };
+            table.add_row(vec![
+                String::from_utf8_lossy(key).to_string(),
+                map.iter()
+                    .rev()
+                    .map(|(ts, (v, _, _))| value_to_string(*ts, *v))
+                    .join("\n"),
+                map.iter()
+                    .rev()
+                    .map(|(ts, (_, v, _))| value_to_string(*ts, *v))
+                    .join("\n"),
+                map.iter()
+                    .rev()
+                    .map(|(ts, (_, _, v))| value_to_string(*ts, *v))
+                    .join("\n"),
+            ]);
+        }
+        write!(f, "{table}")
+    }
+}
+
+/// DurableTable is a `KvTable` that survives restarts.
+///
+/// Every mutation is appended to a write-ahead log, and `sync` waits until
+/// the log is durable. Once the log grows large, the whole table becomes the
+/// snapshot of a new log generation.
+pub struct DurableTable {
+    table: KvTable,
+    wal: Arc<Wal<KvTable>>,
+}
+
+/// A mutation of the table recorded in the log.
+#[derive(Serialize, Deserialize)]
+enum Mutation {
+    Write {
+        key: Vec<u8>,
+        column: Column,
+        ts: u64,
+        value: Value,
+    },
+    Erase {
+        key: Vec<u8>,
+        column: Column,
+        ts: u64,
+    },
+    SetMeta(Vec<u8>),
+}
+
+impl DurableTable {
+    /// Opens the table persisted under `dir`, recovering from its snapshot and log.
+    pub async fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let (wal, table) = Wal::open(dir, |mut table: KvTable, records| {
+            for record in records {
+                let mutation = bincode::deserialize(&record).map_err(io::Error::other)?;
+                table.apply(mutation);
+            }
+            Ok(table)
+        })
+        .await?;
+        Ok(DurableTable {
+            table,
+            wal: Arc::new(wal),
+        })
+    }
+
+    fn record(&mut self, mutation: Mutation) {
+        let record = bincode::serialize(&mutation).expect("failed to encode mutation");
+        self.wal.append(record);
+    }
+}
+
+impl KvTable {
+    fn apply(&mut self, mutation: Mutation) {
+        match mutation {
+            Mutation::Write {
+                key,
+                column,
+                ts,
+                value,
+            } => self.write(key, column, ts, value),
+            Mutation::Erase { key, column, ts } => self.erase(key, column, ts),
+            Mutation::SetMeta(meta) => self.set_meta(meta),
+        }
+    }
+}
+
+impl Storage for DurableTable {
+    const DURABLE: bool = true;
+
+    fn read(
+        &self,
+        key: Vec<u8>,
+        column: Column,
+        ts_range: impl RangeBounds<u64>,
+    ) -> Option<(u64, &Value)> {
+        self.table.read(key, column, ts_range)
+    }
+
+    fn write(&mut self, key: Vec<u8>, column: Column, ts: u64, value: Value) {
+        self.record(Mutation::Write {
+            key: key.clone(),
+            column,
+            ts,
+            value: value.clone(),
+        });
+        self.table.write(key, column, ts, value);
+    }
+
+    fn erase(&mut self, key: Vec<u8>, column: Column, commit_ts: u64) {
+        self.record(Mutation::Erase {
+            key: key.clone(),
+            column,
+            ts: commit_ts,
+        });
+        self.table.erase(key, column, commit_ts);
+    }
+
+    fn find_write(&self, key: Vec<u8>, start_ts: u64) -> Option<u64> {
+        self.table.find_write(key, start_ts)
+    }
+
+    fn scan(
+        &self,
+        column: Column,
+        keys: impl RangeBounds<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = (&Key, &Value)> {
+        self.table.scan(column, keys)
+    }
+
+    fn meta(&self) -> &[u8] {
+        self.table.meta()
+    }
+
+    fn set_meta(&mut self, meta: Vec<u8>) {
+        self.record(Mutation::SetMeta(meta.clone()));
+        self.table.set_meta(meta);
+    }
+
+    fn sync(&mut self) -> impl Future<Output = io::Result<()>> + Send + 'static {
+        if self.wal.size() >= LOG_SIZE_LIMIT {
+            self.wal.rotate(self.table.clone());
+        }
+        let wal = self.wal.clone();
+        let lsn = wal.last_lsn();
+        async move { wal.flush(lsn).await }
+    }
+}
+
+impl Display for DurableTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.table.fmt(f)
+    }
+}
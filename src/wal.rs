@@ -0,0 +1,253 @@
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use madsim::fs::{self, File};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::meta::{checksum, SlotFile};
+
+// The size of the log header: the generation number (8).
+const LOG_HEADER_SIZE: u64 = 8;
+// The size of the record header: payload length (4) and checksum (4).
+const RECORD_HEADER_SIZE: usize = 8;
+
+/// Wal is an append-only write-ahead log shared by concurrent writers.
+///
+/// `append` only buffers a record in memory and returns its log sequence
+/// number (LSN). `flush` waits until a record is durable: the first waiter
+/// writes and syncs every record buffered so far, so the writers arriving
+/// in the meantime share a single fsync.
+///
+/// The log is divided into generations. Each generation starts with a
+/// snapshot of type `S`, and only the records of the same generation are
+/// replayed on top of it during recovery.
+pub struct Wal<S> {
+    pending: Mutex<Pending<S>>,
+    log: futures::lock::Mutex<LogFile>,
+    // The LSN of the last durable record.
+    durable_lsn: AtomicU64,
+}
+
+struct Pending<S> {
+    // The LSN of the last appended record.
+    lsn: u64,
+    // The number of bytes appended since the last rotation.
+    size: u64,
+    entries: Vec<Entry<S>>,
+}
+
+enum Entry<S> {
+    Record(Vec<u8>),
+    Rotate(S),
+}
+
+struct LogFile {
+    dir: PathBuf,
+    snapshot: SlotFile,
+    gen: u64,
+    file: File,
+    len: u64,
+    // Set once a write fails, as the records taken by it are lost.
+    failed: bool,
+}
+
+impl<S: Serialize + DeserializeOwned + Default> Wal<S> {
+    /// Opens the log under `dir`.
+    ///
+    /// `recover` is called with the latest snapshot and the records logged
+    /// after it, and returns the recovered state. The state becomes the
+    /// snapshot of a new generation, which discards a torn record left at
+    /// the end of the log by a crash.
+    pub async fn open(
+        dir: impl AsRef<Path>,
+        recover: impl FnOnce(S, Vec<Vec<u8>>) -> Result<S>,
+    ) -> Result<(Self, S)> {
+        let dir = dir.as_ref().to_path_buf();
+        let (mut snapshot, latest) = SlotFile::open(dir.join("kv-snapshot")).await?;
+        let (gen, state): (u64, S) = latest.unwrap_or_default();
+        let records = match fs::read(log_path(&dir, gen)).await {
+            Ok(data) => {
+                let (records, valid) = decode_log(gen, &data);
+                if valid < data.len() {
+                    tracing::warn!(gen, torn = data.len() - valid, "truncate torn log tail");
+                }
+                records
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+        let replayed = records.len();
+        let state = recover(state, records)?;
+        tracing::info!(gen, replayed, "log recovered");
+        // There is no way to reopen a log for appending, so start a new
+        // generation containing everything recovered so far.
+        let file = new_generation(&dir, &mut snapshot, gen + 1, &state).await?;
+        let log = LogFile {
+            dir,
+            snapshot,
+            gen: gen + 1,
+            file,
+            len: LOG_HEADER_SIZE,
+            failed: false,
+        };
+        let wal = Wal {
+            pending: Mutex::new(Pending {
+                lsn: 0,
+                size: 0,
+                entries: vec![],
+            }),
+            log: futures::lock::Mutex::new(log),
+            durable_lsn: AtomicU64::new(0),
+        };
+        Ok((wal, state))
+    }
+}
+
+impl<S: Serialize> Wal<S> {
+    /// Buffers a record and returns its LSN.
+    pub fn append(&self, record: Vec<u8>) -> u64 {
+        let mut pending = self.pending.lock().unwrap();
+        pending.lsn += 1;
+        pending.size += (RECORD_HEADER_SIZE + record.len()) as u64;
+        pending.entries.push(Entry::Record(record));
+        pending.lsn
+    }
+
+    /// Starts a new generation from `snapshot`, which must reflect all records appended so far.
+    pub fn rotate(&self, snapshot: S) -> u64 {
+        let mut pending = self.pending.lock().unwrap();
+        pending.lsn += 1;
+        pending.size = 0;
+        pending.entries.push(Entry::Rotate(snapshot));
+        pending.lsn
+    }
+
+    /// Returns the LSN of the last appended record.
+    pub fn last_lsn(&self) -> u64 {
+        self.pending.lock().unwrap().lsn
+    }
+
+    /// Returns the number of bytes appended since the last rotation.
+    pub fn size(&self) -> u64 {
+        self.pending.lock().unwrap().size
+    }
+
+    /// Waits until the record with `lsn` and all records before it are durable.
+    pub async fn flush(&self, lsn: u64) -> Result<()> {
+        if self.durable_lsn.load(Ordering::Acquire) >= lsn {
+            return Ok(());
+        }
+        let mut log = self.log.lock().await;
+        // The previous holder of the lock may have flushed our record.
+        if self.durable_lsn.load(Ordering::Acquire) >= lsn {
+            return Ok(());
+        }
+        if log.failed {
+            return Err(Error::other("log failed"));
+        }
+        let (entries, last) = {
+            let mut pending = self.pending.lock().unwrap();
+            (mem::take(&mut pending.entries), pending.lsn)
+        };
+        let count = entries.len();
+        if let Err(e) = log.write(entries).await {
+            log.failed = true;
+            return Err(e);
+        }
+        tracing::trace!(count, lsn = last, "log flushed");
+        self.durable_lsn.store(last, Ordering::Release);
+        Ok(())
+    }
+}
+
+impl LogFile {
+    async fn write<S: Serialize>(&mut self, entries: Vec<Entry<S>>) -> Result<()> {
+        let mut buf = vec![];
+        for entry in entries {
+            match entry {
+                Entry::Record(record) => {
+                    buf.extend_from_slice(&(record.len() as u32).to_le_bytes());
+                    buf.extend_from_slice(&checksum(&record).to_le_bytes());
+                    buf.extend_from_slice(&record);
+                }
+                Entry::Rotate(snapshot) => {
+                    self.append(&mem::take(&mut buf)).await?;
+                    let gen = self.gen + 1;
+                    self.file =
+                        new_generation(&self.dir, &mut self.snapshot, gen, &snapshot).await?;
+                    self.gen = gen;
+                    self.len = LOG_HEADER_SIZE;
+                }
+            }
+        }
+        self.append(&buf).await
+    }
+
+    async fn append(&mut self, buf: &[u8]) -> Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        write_at(&mut self.file, buf, self.len).await?;
+        self.file.sync_all().await?;
+        self.len += buf.len() as u64;
+        Ok(())
+    }
+}
+
+/// Starts generation `gen` with an empty log and a snapshot of `state`.
+async fn new_generation<S: Serialize>(
+    dir: &Path,
+    snapshot: &mut SlotFile,
+    gen: u64,
+    state: &S,
+) -> Result<File> {
+    // The new log only holds its header until the snapshot is saved,
+    // so a crash in between recovers from either generation alike.
+    let mut file = File::create(log_path(dir, gen)).await?;
+    write_at(&mut file, &gen.to_le_bytes(), 0).await?;
+    file.sync_all().await?;
+    snapshot.save(&(gen, state)).await?;
+    Ok(file)
+}
+
+// Logs of two generations alternate between two files, so that starting a
+// new log never truncates the one the latest snapshot refers to.
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("kv-log.{}", gen % 2))
+}
+
+/// Decodes the records in a log of generation `gen`.
+///
+/// Returns the records and the length of the valid prefix of the log.
+/// A log of another generation is ignored. Decoding stops at the first
+/// incomplete or corrupted record, which can only be torn by a crash.
+fn decode_log(gen: u64, data: &[u8]) -> (Vec<Vec<u8>>, usize) {
+    let mut records = vec![];
+    let mut offset = LOG_HEADER_SIZE as usize;
+    match data.get(..offset) {
+        Some(header) if u64::from_le_bytes(header.try_into().unwrap()) == gen => {}
+        _ => return (records, 0),
+    }
+    while let Some(header) = data.get(offset..offset + RECORD_HEADER_SIZE) {
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let sum = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let start = offset + RECORD_HEADER_SIZE;
+        let Some(record) = data.get(start..start + len) else {
+            break;
+        };
+        if checksum(record) != sum {
+            break;
+        }
+        records.push(record.to_vec());
+        offset = start + len;
+    }
+    (records, offset)
+}
+
+// `write_all_at` takes `&mut self` outside the simulator.
+async fn write_at(file: &mut File, buf: &[u8], offset: u64) -> Result<()> {
+    file.write_all_at(buf, offset).await
+}
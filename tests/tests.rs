@@ -1,26 +1,32 @@
 #![cfg(madsim)]
 
+use futures::lock::Mutex as AsyncMutex;
 use madsim::{
-    net::rpc::Request,
+    fs,
+    net::{rpc::Request, Endpoint},
     runtime::{Handle, NodeHandle},
     task, time,
 };
 use spin::Mutex;
 use std::io;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
 };
 use std::time::Duration;
 
-use percolator::client::Client;
-use percolator::msg;
+use percolator::client::{Client, Transaction};
+use percolator::msg::{self, Region};
 use percolator::server::{MemoryStorage, TimestampOracle};
+use percolator::storage::DurableTable;
 
 struct Tester {
     clients: Vec<TestClient>,
     hooks: Arc<CommitHooks>,
+    tso_addrs: Vec<SocketAddr>,
+    txn_addrs: Vec<SocketAddr>,
+    hybrid_clock: Arc<AtomicBool>,
 }
 
 #[derive(Debug, Default)]
@@ -31,7 +37,7 @@ struct CommitHooks {
 }
 
 impl CommitHooks {
-    fn hook_req(&self, req: &msg::CommitRequest) -> bool {
+    fn hook_req(&self, req: &msg::BatchCommitRequest) -> bool {
         if self.drop_secondary_req.load(Ordering::Relaxed)
             && (!req.is_primary || self.drop_primary_req.load(Ordering::Relaxed))
         {
@@ -41,7 +47,7 @@ impl CommitHooks {
         true
     }
 
-    fn hook_rsp(&self, _: &<msg::CommitRequest as Request>::Response) -> bool {
+    fn hook_rsp(&self, _: &<msg::BatchCommitRequest as Request>::Response) -> bool {
         if self.drop_resp.load(Ordering::Relaxed) {
             tracing::debug!("drop a commit response");
             return false;
@@ -52,23 +58,113 @@ impl CommitHooks {
 
 impl Tester {
     async fn new(num_client: usize) -> Self {
+        Self::with_tso_group(num_client, 1).await
+    }
+
+    /// Creates a tester whose TSO is a replicated group of `num_tso` members.
+    async fn with_tso_group(num_client: usize, num_tso: usize) -> Self {
+        Self::build(num_client, num_tso, 1, 1).await
+    }
+
+    /// Creates a tester whose keyspace is split across `num_txn` storage nodes.
+    ///
+    /// Storage node `i` owns the region of keys from `i + 1` up to `i + 2` at
+    /// first, except that the first region starts at the empty key and the
+    /// last one is unbounded.
+    async fn with_shards(num_client: usize, num_txn: usize) -> Self {
+        Self::build(num_client, 1, num_txn, 1).await
+    }
+
+    /// Creates a tester whose storage is a replicated group of `num_replicas` members.
+    async fn with_storage_group(num_client: usize, num_replicas: usize) -> Self {
+        let t = Self::build(num_client, 1, 1, num_replicas).await;
+        t.txn_leader().await;
+        t
+    }
+
+    /// Storage nodes are grouped by shards, each of `num_replicas` members.
+    async fn build(num_client: usize, num_tso: usize, num_txn: usize, num_replicas: usize) -> Self {
         let handle = Handle::current();
 
-        let tso_addr = "10.0.1.1:1".parse::<SocketAddr>().unwrap();
-        let txn_addr = "10.0.1.2:1".parse::<SocketAddr>().unwrap();
-
-        handle
-            .create_node()
-            .name("tso")
-            .ip(tso_addr.ip())
-            .init(move || TimestampOracle::default().serve(tso_addr))
-            .build();
-        handle
-            .create_node()
-            .name("txn")
-            .ip(txn_addr.ip())
-            .init(move || MemoryStorage::default().serve(txn_addr))
-            .build();
+        let tso_addrs = (1..=num_tso)
+            .map(|i| SocketAddr::from(([10, 0, 1, i as u8], 1)))
+            .collect::<Vec<_>>();
+        let txn_addrs = (1..=num_txn * num_replicas)
+            .map(|i| SocketAddr::from(([10, 0, 2, i as u8], 1)))
+            .collect::<Vec<_>>();
+        let hybrid_clock = Arc::new(AtomicBool::new(false));
+
+        for (i, tso_addr) in tso_addrs.iter().cloned().enumerate() {
+            let peers = tso_addrs.clone();
+            let hybrid_clock = hybrid_clock.clone();
+            handle
+                .create_node()
+                .name(tso_name(num_tso, i))
+                .ip(tso_addr.ip())
+                .init(move || {
+                    let peers = peers.clone();
+                    let hybrid_clock = hybrid_clock.load(Ordering::Relaxed);
+                    async move {
+                        let standalone = peers.len() == 1;
+                        let mut tso = match standalone {
+                            true => TimestampOracle::open("data").await?,
+                            false => TimestampOracle::open_group("data", peers, i).await?,
+                        };
+                        if hybrid_clock {
+                            tso = tso.with_hybrid_clock();
+                        }
+                        match standalone {
+                            true => tso.serve(tso_addr).await,
+                            false => tso.serve_group().await,
+                        }
+                    }
+                })
+                .build();
+        }
+        for (j, txn_addr) in txn_addrs.iter().cloned().enumerate() {
+            let (i, me) = (j / num_replicas, j % num_replicas);
+            let peers = txn_addrs[i * num_replicas..(i + 1) * num_replicas].to_vec();
+            let nodes = txn_addrs.clone();
+            let key = |i: usize| match i {
+                0 => vec![],
+                _ if i == num_txn => vec![],
+                _ => (i + 1).to_string().into_bytes(),
+            };
+            let region = Region {
+                id: i as u64 + 1,
+                start_key: key(i),
+                end_key: key(i + 1),
+                version: 1,
+            };
+            handle
+                .create_node()
+                .name(txn_name(txn_addrs.len(), j))
+                .ip(txn_addr.ip())
+                .init(move || {
+                    let region = region.clone();
+                    let peers = peers.clone();
+                    let nodes = nodes.clone();
+                    async move {
+                        let table = DurableTable::open("data").await?;
+                        if peers.len() > 1 {
+                            let storage =
+                                MemoryStorage::open_group(table, vec![region], "raft", peers, me)
+                                    .await?
+                                    .with_storage_nodes(nodes);
+                            return storage.serve_group().await;
+                        }
+                        let storage = MemoryStorage::with_regions(table, vec![region])
+                            .with_storage_nodes(nodes);
+                        // The simulator picks ephemeral ports among the sockets bound to
+                        // the same IP, so listen on the unspecified address to keep the
+                        // endpoints used for moving regions off the service port.
+                        storage
+                            .serve((Ipv4Addr::UNSPECIFIED, txn_addr.port()).into())
+                            .await
+                    }
+                })
+                .build();
+        }
 
         let net = madsim::net::NetSim::current();
         let hooks = Arc::new(CommitHooks::default());
@@ -80,7 +176,7 @@ impl Tester {
                 .ip([10, 0, 0, i as u8].into())
                 .build();
             let client = Arc::new(Mutex::new(
-                node.spawn(Client::new(tso_addr, txn_addr))
+                node.spawn(Client::new(tso_addrs.clone(), txn_addrs.clone()))
                     .await
                     .unwrap()
                     .expect("failed to create client"),
@@ -89,15 +185,160 @@ impl Tester {
             let hooks2 = hooks.clone();
             net.hook_rpc_req(node.id(), move |req| hooks1.hook_req(req));
             net.hook_rpc_rsp(node.id(), move |rsp| hooks2.hook_rsp(rsp));
-            clients.push(TestClient { node, client });
+            let txn = Arc::new(AsyncMutex::new(None));
+            clients.push(TestClient { node, client, txn });
+        }
+        Tester {
+            clients,
+            hooks,
+            tso_addrs,
+            txn_addrs,
+            hybrid_clock,
         }
-        Tester { clients, hooks }
     }
 
     fn client(&self, i: usize) -> TestClient {
         self.clients[i].clone()
     }
 
+    /// Restarts the node of client `i` and replaces its client with a fresh one.
+    async fn restart_client(&mut self, i: usize) {
+        tracing::info!(i, "restart client");
+        let handle = Handle::current();
+        let TestClient { node, client, txn } = &mut self.clients[i];
+        handle.kill(node.id());
+        handle.restart(node.id());
+        *node = handle.get_node(node.id()).unwrap();
+        let new_client = node
+            .spawn(Client::new(self.tso_addrs.clone(), self.txn_addrs.clone()))
+            .await
+            .unwrap()
+            .expect("failed to create client");
+        *client.lock() = new_client;
+        *txn.lock().await = None;
+    }
+
+    /// Restarts the TSO with a hybrid clock.
+    fn set_hybrid_clock(&self) {
+        self.hybrid_clock.store(true, Ordering::Relaxed);
+        self.kill_tso();
+        self.restart_tso();
+    }
+
+    fn kill_tso(&self) {
+        for i in 0..self.tso_addrs.len() {
+            self.kill_tso_member(i);
+        }
+    }
+
+    fn restart_tso(&self) {
+        for i in 0..self.tso_addrs.len() {
+            self.restart_tso_member(i);
+        }
+    }
+
+    fn kill_tso_member(&self, i: usize) {
+        tracing::info!(i, "kill tso");
+        Handle::current().kill(tso_name(self.tso_addrs.len(), i));
+    }
+
+    fn restart_tso_member(&self, i: usize) {
+        tracing::info!(i, "restart tso");
+        Handle::current().restart(tso_name(self.tso_addrs.len(), i));
+    }
+
+    fn restart_txn(&self) {
+        tracing::info!("restart txn");
+        let handle = Handle::current();
+        for i in 0..self.txn_addrs.len() {
+            handle.kill(txn_name(self.txn_addrs.len(), i));
+            handle.restart(txn_name(self.txn_addrs.len(), i));
+        }
+    }
+
+    fn kill_txn_member(&self, i: usize) {
+        tracing::info!(i, "kill txn");
+        Handle::current().kill(txn_name(self.txn_addrs.len(), i));
+    }
+
+    fn restart_txn_member(&self, i: usize) {
+        tracing::info!(i, "restart txn");
+        Handle::current().restart(txn_name(self.txn_addrs.len(), i));
+    }
+
+    /// Sends a request to the first storage node, bypassing the client.
+    async fn call_txn<R>(&self, req: R) -> R::Response
+    where
+        R: Request + Send + 'static,
+        R::Response: Send,
+    {
+        self.call_txn_node(0, req).await
+    }
+
+    /// Sends a request to storage node `j`, bypassing the client.
+    async fn call_txn_node<R>(&self, j: usize, req: R) -> R::Response
+    where
+        R: Request + Send + 'static,
+        R::Response: Send,
+    {
+        let addr = self.txn_addrs[j];
+        self.clients[0]
+            .node
+            .spawn(async move {
+                let ep = Endpoint::bind("0.0.0.0:0").await.unwrap();
+                ep.call(addr, req).await.unwrap()
+            })
+            .await
+            .unwrap()
+    }
+
+    /// Waits until a storage node leads its group and returns it.
+    async fn txn_leader(&self) -> usize {
+        let addrs = self.txn_addrs.clone();
+        self.clients[0]
+            .node
+            .spawn(async move {
+                let ep = Endpoint::bind("0.0.0.0:0").await.unwrap();
+                loop {
+                    for (i, &addr) in addrs.iter().enumerate() {
+                        let req = msg::RegionsRequest {};
+                        let rsp = ep.call_timeout(addr, req, Duration::from_millis(100));
+                        if rsp.await.is_ok_and(|rsp| rsp.leader == Some(addr)) {
+                            return i;
+                        }
+                    }
+                    time::sleep(Duration::from_millis(100)).await;
+                }
+            })
+            .await
+            .unwrap()
+    }
+
+    /// Appends a torn record to the latest write-ahead log of the storage.
+    async fn tear_txn_log(&self) {
+        tracing::info!("tear txn log");
+        let node = Handle::current().get_node("txn").unwrap();
+        node.spawn(async {
+            let mut latest: Option<(u64, &str, Vec<u8>)> = None;
+            for path in ["data/kv-log.0", "data/kv-log.1"] {
+                if let Ok(data) = fs::read(path).await {
+                    let gen = u64::from_le_bytes(data[..8].try_into().unwrap());
+                    if latest.as_ref().is_none_or(|(g, _, _)| gen > *g) {
+                        latest = Some((gen, path, data));
+                    }
+                }
+            }
+            let (_, path, mut data) = latest.unwrap();
+            // the header of a 42-byte record followed by a part of it
+            data.extend_from_slice(&[42, 0, 0, 0, 1, 2, 3, 4, 5, 6]);
+            let file = fs::File::create(path).await.unwrap();
+            file.write_all_at(&data, 0).await.unwrap();
+            file.sync_all().await.unwrap();
+        })
+        .await
+        .unwrap();
+    }
+
     fn enable_client(&self, i: usize) {
         tracing::info!(i, "enable client");
         let net = madsim::net::NetSim::current();
@@ -110,6 +351,37 @@ impl Tester {
         net.clog_node(self.clients[i].node.id());
     }
 
+    /// Cuts storage nodes `i` and `j` off from each other, or connects them again.
+    fn clog_txn_to_txn(&self, i: usize, j: usize, clogged: bool) {
+        tracing::info!(i, j, clogged, "clog txn to txn");
+        let handle = Handle::current();
+        let a = handle.get_node(txn_name(self.txn_addrs.len(), i)).unwrap();
+        let b = handle.get_node(txn_name(self.txn_addrs.len(), j)).unwrap();
+        let net = madsim::net::NetSim::current();
+        match clogged {
+            true => {
+                net.clog_link(a.id(), b.id());
+                net.clog_link(b.id(), a.id());
+            }
+            false => {
+                net.unclog_link(a.id(), b.id());
+                net.unclog_link(b.id(), a.id());
+            }
+        }
+    }
+
+    /// Cuts client `i` off from storage node `j`, or connects it again.
+    fn clog_client_to_txn(&self, i: usize, j: usize, clogged: bool) {
+        tracing::info!(i, j, clogged, "clog client to txn");
+        let handle = Handle::current();
+        let txn = handle.get_node(txn_name(self.txn_addrs.len(), j)).unwrap();
+        let net = madsim::net::NetSim::current();
+        match clogged {
+            true => net.clog_link(self.clients[i].node.id(), txn.id()),
+            false => net.unclog_link(self.clients[i].node.id(), txn.id()),
+        }
+    }
+
     fn drop_commit_primary_request(&self) {
         tracing::info!("set drop commit primary request");
         self.hooks.drop_primary_req.store(true, Ordering::Relaxed);
@@ -135,48 +407,178 @@ impl Tester {
     }
 }
 
+fn tso_name(num_tso: usize, i: usize) -> String {
+    match num_tso {
+        1 => "tso".into(),
+        _ => format!("tso-{}", i + 1),
+    }
+}
+
+fn txn_name(num_txn: usize, i: usize) -> String {
+    match num_txn {
+        1 => "txn".into(),
+        _ => format!("txn-{}", i + 1),
+    }
+}
+
 #[derive(Clone)]
 struct TestClient {
     node: NodeHandle,
     client: Arc<Mutex<Client>>,
+    // The transaction begun last, until it is committed.
+    // Its lock is held across awaits, so it must yield to other tasks.
+    txn: Arc<AsyncMutex<Option<Transaction>>>,
 }
 
 impl TestClient {
     async fn get_timestamp(&self) -> io::Result<u64> {
-        let client = self.client.clone();
+        let client = self.client.lock().clone();
         self.node
-            .spawn(async move { client.lock().get_timestamp().await })
+            .spawn(async move { client.get_timestamp().await })
             .await
             .unwrap()
     }
+    fn set_timestamp_batch(&self, count: u32) {
+        self.client.lock().set_timestamp_batch(count);
+    }
+    fn set_follower_read(&self, enabled: bool) {
+        self.client.lock().set_follower_read(enabled);
+    }
+    fn set_async_commit(&self, enabled: bool) {
+        self.client.lock().set_async_commit(enabled);
+    }
+    fn set_one_phase_commit(&self, enabled: bool) {
+        self.client.lock().set_one_phase_commit(enabled);
+    }
+    fn set_lock_ttl(&self, ttl: Duration) {
+        self.client.lock().set_lock_ttl(ttl);
+    }
     async fn begin(&mut self) {
-        let client = self.client.clone();
-        self.node
-            .spawn(async move { client.lock().begin().await })
+        let client = self.client.lock().clone();
+        let txn = self
+            .node
+            .spawn(async move { client.begin().await })
             .await
             .unwrap()
+            .unwrap();
+        assert!(
+            self.txn.lock().await.replace(txn).is_none(),
+            "transaction already begin"
+        );
+    }
+    async fn start_ts(&self) -> u64 {
+        self.txn
+            .lock()
+            .await
+            .as_ref()
+            .expect("no transaction")
+            .start_ts()
     }
-    async fn get(&self, key: &[u8]) -> io::Result<Vec<u8>> {
-        let client = self.client.clone();
+    async fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let txn = self.txn.clone();
         let key = key.to_vec();
         self.node
-            .spawn(async move { client.lock().get(&key).await })
+            .spawn(async move {
+                let txn = txn.lock().await;
+                txn.as_ref().expect("no transaction").get(&key).await
+            })
+            .await
+            .unwrap()
+    }
+    async fn batch_get(&self, keys: &[&[u8]]) -> io::Result<Vec<Option<Vec<u8>>>> {
+        let txn = self.txn.clone();
+        let keys = keys.iter().map(|key| key.to_vec()).collect::<Vec<_>>();
+        self.node
+            .spawn(async move {
+                let keys = keys.iter().map(|key| &key[..]).collect::<Vec<_>>();
+                let txn = txn.lock().await;
+                txn.as_ref().expect("no transaction").batch_get(&keys).await
+            })
+            .await
+            .unwrap()
+    }
+    async fn scan(&self, start: &[u8], end: &[u8], limit: usize) -> io::Result<Vec<msg::KvPair>> {
+        let txn = self.txn.clone();
+        let (start, end) = (start.to_vec(), end.to_vec());
+        self.node
+            .spawn(async move {
+                let txn = txn.lock().await;
+                let txn = txn.as_ref().expect("no transaction");
+                txn.scan(&start[..]..&end[..], limit).await
+            })
+            .await
+            .unwrap()
+    }
+    async fn scan_reverse(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        limit: usize,
+    ) -> io::Result<Vec<msg::KvPair>> {
+        let txn = self.txn.clone();
+        let (start, end) = (start.to_vec(), end.to_vec());
+        self.node
+            .spawn(async move {
+                let txn = txn.lock().await;
+                let txn = txn.as_ref().expect("no transaction");
+                txn.scan_reverse(&start[..]..&end[..], limit).await
+            })
             .await
             .unwrap()
     }
     async fn set(&mut self, key: &[u8], value: &[u8]) {
-        let client = self.client.clone();
+        let txn = self.txn.clone();
         let key = key.to_vec();
         let value = value.to_vec();
         self.node
-            .spawn(async move { client.lock().set(&key, &value).await })
+            .spawn(async move {
+                let mut txn = txn.lock().await;
+                txn.as_mut()
+                    .expect("no transaction")
+                    .set(&key, &value)
+                    .await
+            })
+            .await
+            .unwrap()
+    }
+    async fn delete(&mut self, key: &[u8]) {
+        let txn = self.txn.clone();
+        let key = key.to_vec();
+        self.node
+            .spawn(async move {
+                let mut txn = txn.lock().await;
+                txn.as_mut().expect("no transaction").delete(&key).await
+            })
             .await
             .unwrap()
     }
     async fn commit(&self) -> io::Result<bool> {
-        let client = self.client.clone();
+        let txn = self.txn.lock().await.take().expect("no transaction");
+        self.node
+            .spawn(async move { txn.commit().await })
+            .await
+            .unwrap()
+    }
+    async fn rollback(&self) -> io::Result<()> {
+        let txn = self.txn.lock().await.take().expect("no transaction");
+        self.node
+            .spawn(async move { txn.rollback().await })
+            .await
+            .unwrap()
+    }
+    async fn split_region(&self, split_key: &[u8], target: Option<SocketAddr>) -> io::Result<()> {
+        let client = self.client.lock().clone();
+        let split_key = split_key.to_vec();
+        self.node
+            .spawn(async move { client.split_region(&split_key, target).await })
+            .await
+            .unwrap()
+    }
+    async fn merge_region(&self, key: &[u8]) -> io::Result<()> {
+        let client = self.client.lock().clone();
+        let key = key.to_vec();
         self.node
-            .spawn(async move { client.lock().commit().await })
+            .spawn(async move { client.merge_region(&key).await })
             .await
             .unwrap()
     }
@@ -212,6 +614,157 @@ async fn test_get_timestamp_under_unreliable_network() {
     }
 }
 
+#[madsim::test]
+async fn test_get_timestamp_batch() {
+    let mut t = Tester::new(2).await;
+    let client0 = t.client(0);
+    let client1 = t.client(1);
+    client0.set_timestamp_batch(8);
+
+    let mut last = None;
+    for _ in 0..20 {
+        let ts = client0.get_timestamp().await.unwrap();
+        assert!(last < Some(ts));
+        last = Some(ts);
+    }
+    // the other client never gets a timestamp reserved by client 0
+    let ts1 = client1.get_timestamp().await.unwrap();
+    assert!(ts1 > last.unwrap());
+
+    // a restarted client must not reuse timestamps from its old cache
+    t.restart_client(0).await;
+    let client0 = t.client(0);
+    client0.set_timestamp_batch(8);
+    assert!(client0.get_timestamp().await.unwrap() > ts1);
+}
+
+#[madsim::test]
+async fn test_commit_with_timestamp_batch() {
+    let t = Tester::new(2).await;
+    let mut client0 = t.client(0);
+    let mut client1 = t.client(1);
+    client0.set_timestamp_batch(100);
+    // fill the cache of client 0
+    client0.get_timestamp().await.unwrap();
+
+    client1.begin().await;
+    assert_eq!(client1.get(b"1").await.unwrap(), None);
+
+    client0.begin().await;
+    client0.set(b"1", b"10").await;
+    assert!(client0.commit().await.unwrap());
+
+    // the commit is above the start timestamp of client 1
+    assert_eq!(client1.get(b"1").await.unwrap(), None);
+    assert!(client1.commit().await.unwrap());
+
+    // and below the next start timestamp of client 0
+    client0.begin().await;
+    assert_eq!(client0.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    assert!(client0.commit().await.unwrap());
+}
+
+#[madsim::test]
+async fn test_get_timestamp_after_tso_restart() {
+    let t = Tester::new(2).await;
+    let client0 = t.client(0);
+    let client1 = t.client(1);
+    client1.set_timestamp_batch(100);
+
+    let mut last = None;
+    for _ in 0..3 {
+        for _ in 0..10 {
+            let ts = client0.get_timestamp().await.unwrap();
+            assert!(Some(ts) > last);
+            last = Some(ts);
+        }
+        last = last.max(Some(client1.get_timestamp().await.unwrap()));
+
+        t.kill_tso();
+        assert!(client0.get_timestamp().await.is_err());
+        t.restart_tso();
+        // wait for the tso to recover
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(Some(client0.get_timestamp().await.unwrap()) > last);
+}
+
+#[madsim::test]
+async fn test_hybrid_timestamp() {
+    let t = Tester::new(1).await;
+    t.set_hybrid_clock();
+    time::sleep(Duration::from_millis(100)).await;
+    let client0 = t.client(0);
+
+    let now = || {
+        let now = std::time::SystemTime::now();
+        now.duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    };
+    let ts = client0.get_timestamp().await.unwrap();
+    assert!(msg::extract_physical(ts).abs_diff(now()) < 1000);
+    assert_eq!(
+        msg::compose_ts(msg::extract_physical(ts), msg::extract_logical(ts)),
+        ts
+    );
+
+    // after a restart the TSO resumes above its reserved window, which is ahead
+    // of the clock, so it must keep counting on the logical part
+    let mut last = ts;
+    t.kill_tso();
+    t.restart_tso();
+    time::sleep(Duration::from_millis(100)).await;
+    for _ in 0..10 {
+        let ts = client0.get_timestamp().await.unwrap();
+        assert!(ts > last);
+        last = ts;
+    }
+    assert!(msg::extract_physical(last) > now());
+}
+
+#[madsim::test]
+async fn test_tso_group_failover() {
+    let t = Tester::with_tso_group(3, 3).await;
+    // wait for a leader
+    time::sleep(Duration::from_secs(2)).await;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut children = vec![];
+    for i in 0..2 {
+        let client = t.client(i);
+        let stop = stop.clone();
+        children.push(task::spawn(async move {
+            let mut last = None;
+            while !stop.load(Ordering::Relaxed) {
+                if let Ok(ts) = client.get_timestamp().await {
+                    assert!(Some(ts) > last, "timestamp goes backwards");
+                    last = Some(ts);
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+            last.unwrap()
+        }));
+    }
+
+    for round in 0..6 {
+        let i = round % 3;
+        t.kill_tso_member(i);
+        time::sleep(Duration::from_secs(2)).await;
+        // the remaining majority keeps serving
+        t.client(2).get_timestamp().await.unwrap();
+        t.restart_tso_member(i);
+        time::sleep(Duration::from_millis(500)).await;
+    }
+    stop.store(true, Ordering::Relaxed);
+    let mut last = 0;
+    for child in children {
+        last = last.max(child.await.unwrap());
+    }
+    // timestamps never go backwards across clients and failovers
+    assert!(t.client(2).get_timestamp().await.unwrap() > last);
+}
+
 // https://github.com/ept/hermitage/blob/master/sqlserver.md#predicate-many-preceders-pmp
 #[madsim::test]
 async fn test_predicate_many_preceders_read_predicates() {
@@ -225,14 +778,14 @@ async fn test_predicate_many_preceders_read_predicates() {
 
     let mut client1 = t.client(1);
     client1.begin().await;
-    assert_eq!(client1.get(b"3").await.unwrap(), b"");
+    assert_eq!(client1.get(b"3").await.unwrap(), None);
 
     let mut client2 = t.client(2);
     client2.begin().await;
     client2.set(b"3", b"30").await;
     assert!(client2.commit().await.unwrap());
 
-    assert_eq!(client1.get(b"3").await.unwrap(), b"");
+    assert_eq!(client1.get(b"3").await.unwrap(), None);
 }
 
 // https://github.com/ept/hermitage/blob/master/sqlserver.md#predicate-many-preceders-pmp
@@ -254,13 +807,72 @@ async fn test_predicate_many_preceders_write_predicates() {
 
     client1.set(b"1", b"20").await;
     client1.set(b"2", b"30").await;
-    assert_eq!(client1.get(b"2").await.unwrap(), b"20");
+    assert_eq!(client1.get(b"2").await.unwrap(), Some(b"20".to_vec()));
 
     client2.set(b"2", b"40").await;
     assert!(client1.commit().await.unwrap());
     assert!(!client2.commit().await.unwrap());
 }
 
+#[madsim::test]
+async fn test_storage_survives_restart() {
+    let t = Tester::new(3).await;
+
+    let mut client0 = t.client(0);
+    client0.begin().await;
+    client0.set(b"1", b"10").await;
+    client0.set(b"2", b"20").await;
+    assert!(client0.commit().await.unwrap());
+
+    // leave the locks of the secondaries behind
+    let mut client1 = t.client(1);
+    client1.begin().await;
+    client1.set(b"3", b"30").await;
+    client1.set(b"4", b"40").await;
+    t.drop_commit_secondary_request();
+    assert!(client1.commit().await.unwrap());
+    t.reset_drop();
+
+    t.restart_txn();
+    time::sleep(Duration::from_secs(1)).await;
+
+    let mut client2 = t.client(2);
+    client2.begin().await;
+    assert_eq!(client2.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    assert_eq!(client2.get(b"2").await.unwrap(), Some(b"20".to_vec()));
+    assert_eq!(client2.get(b"3").await.unwrap(), Some(b"30".to_vec()));
+    assert_eq!(client2.get(b"4").await.unwrap(), Some(b"40".to_vec()));
+}
+
+#[madsim::test]
+async fn test_storage_truncates_torn_log() {
+    let t = Tester::new(3).await;
+
+    let mut client0 = t.client(0);
+    client0.begin().await;
+    client0.set(b"1", b"10").await;
+    assert!(client0.commit().await.unwrap());
+
+    t.tear_txn_log().await;
+    t.restart_txn();
+    time::sleep(Duration::from_secs(1)).await;
+
+    let mut client1 = t.client(1);
+    client1.begin().await;
+    assert_eq!(client1.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    client1.set(b"2", b"20").await;
+    assert!(client1.commit().await.unwrap());
+
+    // the log keeps working after the torn record is dropped
+    t.restart_txn();
+    time::sleep(Duration::from_secs(1)).await;
+
+    let mut client2 = t.client(2);
+    client2.begin().await;
+    assert_eq!(client2.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    assert_eq!(client2.get(b"2").await.unwrap(), Some(b"20".to_vec()));
+}
+
 // https://github.com/ept/hermitage/blob/master/sqlserver.md#lost-update-p4
 #[madsim::test]
 async fn test_lost_update() {
@@ -278,8 +890,8 @@ async fn test_lost_update() {
     let mut client2 = t.client(2);
     client2.begin().await;
 
-    assert_eq!(client1.get(b"1").await.unwrap(), b"10");
-    assert_eq!(client2.get(b"1").await.unwrap(), b"10");
+    assert_eq!(client1.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    assert_eq!(client2.get(b"1").await.unwrap(), Some(b"10".to_vec()));
 
     client1.set(b"1", b"11").await;
     client2.set(b"1", b"11").await;
@@ -304,15 +916,15 @@ async fn test_read_skew_read_only() {
     let mut client2 = t.client(2);
     client2.begin().await;
 
-    assert_eq!(client1.get(b"1").await.unwrap(), b"10");
-    assert_eq!(client2.get(b"1").await.unwrap(), b"10");
-    assert_eq!(client2.get(b"2").await.unwrap(), b"20");
+    assert_eq!(client1.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    assert_eq!(client2.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    assert_eq!(client2.get(b"2").await.unwrap(), Some(b"20".to_vec()));
 
     client2.set(b"1", b"12").await;
     client2.set(b"2", b"18").await;
     assert!(client2.commit().await.unwrap());
 
-    assert_eq!(client1.get(b"2").await.unwrap(), b"20");
+    assert_eq!(client1.get(b"2").await.unwrap(), Some(b"20".to_vec()));
 }
 
 // https://github.com/ept/hermitage/blob/master/sqlserver.md#read-skew-g-single
@@ -332,13 +944,13 @@ async fn test_read_skew_predicate_dependencies() {
     let mut client2 = t.client(2);
     client2.begin().await;
 
-    assert_eq!(client1.get(b"1").await.unwrap(), b"10");
-    assert_eq!(client1.get(b"2").await.unwrap(), b"20");
+    assert_eq!(client1.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    assert_eq!(client1.get(b"2").await.unwrap(), Some(b"20".to_vec()));
 
     client2.set(b"3", b"30").await;
     assert!(client2.commit().await.unwrap());
 
-    assert_eq!(client1.get(b"3").await.unwrap(), b"");
+    assert_eq!(client1.get(b"3").await.unwrap(), None);
 }
 
 // https://github.com/ept/hermitage/blob/master/sqlserver.md#read-skew-g-single
@@ -358,9 +970,9 @@ async fn test_read_skew_write_predicate() {
     let mut client2 = t.client(2);
     client2.begin().await;
 
-    assert_eq!(client1.get(b"1").await.unwrap(), b"10");
-    assert_eq!(client2.get(b"1").await.unwrap(), b"10");
-    assert_eq!(client2.get(b"2").await.unwrap(), b"20");
+    assert_eq!(client1.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    assert_eq!(client2.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    assert_eq!(client2.get(b"2").await.unwrap(), Some(b"20".to_vec()));
 
     client2.set(b"1", b"12").await;
     client2.set(b"2", b"18").await;
@@ -387,10 +999,10 @@ async fn test_write_skew() {
     let mut client2 = t.client(2);
     client2.begin().await;
 
-    assert_eq!(client1.get(b"1").await.unwrap(), b"10");
-    assert_eq!(client1.get(b"2").await.unwrap(), b"20");
-    assert_eq!(client2.get(b"1").await.unwrap(), b"10");
-    assert_eq!(client2.get(b"2").await.unwrap(), b"20");
+    assert_eq!(client1.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    assert_eq!(client1.get(b"2").await.unwrap(), Some(b"20".to_vec()));
+    assert_eq!(client2.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    assert_eq!(client2.get(b"2").await.unwrap(), Some(b"20".to_vec()));
 
     client1.set(b"1", b"11").await;
     client2.set(b"2", b"21").await;
@@ -424,8 +1036,8 @@ async fn test_anti_dependency_cycles() {
 
     let mut client3 = t.client(3);
     client3.begin().await;
-    assert_eq!(client3.get(b"3").await.unwrap(), b"30");
-    assert_eq!(client3.get(b"4").await.unwrap(), b"42");
+    assert_eq!(client3.get(b"3").await.unwrap(), Some(b"30".to_vec()));
+    assert_eq!(client3.get(b"4").await.unwrap(), Some(b"42".to_vec()));
 }
 
 #[madsim::test]
@@ -443,9 +1055,322 @@ async fn test_commit_primary_drop_secondary_reqs() {
 
     let mut client1 = t.client(1);
     client1.begin().await;
-    assert_eq!(client1.get(b"3").await.unwrap(), b"30");
-    assert_eq!(client1.get(b"4").await.unwrap(), b"40");
-    assert_eq!(client1.get(b"5").await.unwrap(), b"50");
+    assert_eq!(client1.get(b"3").await.unwrap(), Some(b"30".to_vec()));
+    assert_eq!(client1.get(b"4").await.unwrap(), Some(b"40".to_vec()));
+    assert_eq!(client1.get(b"5").await.unwrap(), Some(b"50".to_vec()));
+}
+
+#[madsim::test]
+async fn test_resolve_lock() {
+    let t = Tester::new(2).await;
+    let region = t.call_txn(msg::RegionsRequest {}).await.regions[0].epoch();
+    let keys: Vec<&[u8]> = vec![b"3", b"4", b"5", b"6"];
+
+    // the secondaries of a committed transaction stay locked
+    let mut client0 = t.client(0);
+    client0.begin().await;
+    for key in &keys {
+        client0.set(key, b"30").await;
+    }
+    let start_ts = client0.start_ts().await;
+    t.drop_commit_secondary_request();
+    assert!(client0.commit().await.unwrap());
+    // wait for the secondaries to be given up
+    client0.begin().await;
+    t.reset_drop();
+    let check_locks = |keys: &[&[u8]], start_ts| msg::CheckLocksRequest {
+        region,
+        keys: keys.iter().map(|key| key.to_vec()).collect(),
+        start_ts,
+    };
+    let rsp = t.call_txn(check_locks(&keys[1..], start_ts)).await.unwrap();
+    assert!(rsp
+        .iter()
+        .all(|status| matches!(status, Ok(msg::LockStatus::Locked { .. }))));
+
+    // reading one of them resolves all the locks of the transaction
+    let mut client1 = t.client(1);
+    client1.begin().await;
+    assert_eq!(client1.get(b"4").await.unwrap(), Some(b"30".to_vec()));
+    let rsp = t.call_txn(check_locks(&keys[1..], start_ts)).await.unwrap();
+    assert!(rsp
+        .iter()
+        .all(|status| matches!(status, Ok(msg::LockStatus::Committed { .. }))));
+
+    // only the given keys are rolled back
+    let ts = client1.get_timestamp().await.unwrap();
+    for key in &keys {
+        (t.call_txn(msg::PrewriteRequest {
+            region,
+            start_ts: ts,
+            key: key.to_vec(),
+            value: b"40".to_vec(),
+            primary_key: b"3".to_vec(),
+            lock_ttl: 0,
+            start_time: 0,
+        }))
+        .await
+        .unwrap();
+    }
+    let resolve = |keys: &[&[u8]]| msg::ResolveLockRequest {
+        region,
+        start_ts: ts,
+        commit_ts: None,
+        keys: keys.iter().map(|key| key.to_vec()).collect(),
+    };
+    assert_eq!(t.call_txn(resolve(&keys[..2])).await.unwrap(), 2);
+    assert_eq!(t.call_txn(resolve(&[])).await.unwrap(), 2);
+    let rsp = t.call_txn(check_locks(&keys, ts)).await.unwrap();
+    assert!(rsp
+        .iter()
+        .all(|status| matches!(status, Ok(msg::LockStatus::Missing))));
+}
+
+#[madsim::test]
+async fn test_lock_gc() {
+    let t = Tester::new(1).await;
+    let region = t.call_txn(msg::RegionsRequest {}).await.regions[0].epoch();
+    let client = t.client(0);
+    let ts0 = client.get_timestamp().await.unwrap();
+    let ts1 = client.get_timestamp().await.unwrap();
+    let ts2 = client.get_timestamp().await.unwrap();
+    let ts3 = client.get_timestamp().await.unwrap();
+
+    // the locks left by crashed clients, and one of a live client
+    // whose TTL runs past the end of time
+    let prewrite = |key: &[u8], primary: &[u8], start_ts, lock_ttl| msg::PrewriteRequest {
+        region,
+        start_ts,
+        key: key.to_vec(),
+        value: b"10".to_vec(),
+        primary_key: primary.to_vec(),
+        lock_ttl,
+        start_time: 1,
+    };
+    for (key, primary, start_ts, lock_ttl) in [
+        (b"3", b"3", ts0, 0),
+        (b"4", b"3", ts0, 0),
+        (b"5", b"3", ts0, 0),
+        (b"6", b"6", ts1, 0),
+        (b"7", b"6", ts1, 0),
+        (b"8", b"8", ts2, u64::MAX),
+    ] {
+        (t.call_txn(prewrite(key, primary, start_ts, lock_ttl)))
+            .await
+            .unwrap();
+    }
+    (t.call_txn(msg::CommitRequest {
+        region,
+        is_primary: true,
+        key: b"3".to_vec(),
+        start_ts: ts0,
+        commit_ts: ts3,
+    }))
+    .await
+    .unwrap();
+
+    // the collector commits or rolls back the expired locks as their primary keys tell
+    time::sleep(Duration::from_secs(3)).await;
+    let stats = t.call_txn(msg::LockGcStatsRequest {}).await;
+    assert_eq!(
+        stats,
+        msg::LockGcStats {
+            found: 4,
+            cleaned: 4
+        }
+    );
+    let check_locks = |keys: &[&[u8]], start_ts| msg::CheckLocksRequest {
+        region,
+        keys: keys.iter().map(|key| key.to_vec()).collect(),
+        start_ts,
+    };
+    let rsp = t.call_txn(check_locks(&[b"4", b"5"], ts0)).await.unwrap();
+    assert!(rsp.iter().all(
+        |status| matches!(status, Ok(msg::LockStatus::Committed { commit_ts }) if *commit_ts == ts3)
+    ));
+    let rsp = t.call_txn(check_locks(&[b"6", b"7"], ts1)).await.unwrap();
+    assert!(rsp
+        .iter()
+        .all(|status| matches!(status, Ok(msg::LockStatus::Missing))));
+    let rsp = t.call_txn(check_locks(&[b"8"], ts2)).await.unwrap();
+    assert!(matches!(rsp[..], [Ok(msg::LockStatus::Locked { .. })]));
+}
+
+#[madsim::test]
+async fn test_lock_gc_async_commit() {
+    let t = Tester::new(1).await;
+    let region = t.call_txn(msg::RegionsRequest {}).await.regions[0].epoch();
+    let client = t.client(0);
+    let ts0 = client.get_timestamp().await.unwrap();
+    let ts1 = client.get_timestamp().await.unwrap();
+    let ts2 = client.get_timestamp().await.unwrap();
+
+    // the locks left by async-commit clients crashed while prewriting
+    let prewrite = |keys: &[&[u8]], primary: &[u8], secondaries: &[&[u8]], start_ts| {
+        msg::BatchPrewriteRequest {
+            region,
+            start_ts,
+            mutations: (keys.iter())
+                .map(|key| msg::Mutation {
+                    key: key.to_vec(),
+                    value: Some(b"10".to_vec()),
+                })
+                .collect(),
+            primary_key: primary.to_vec(),
+            async_commit: true,
+            secondaries: secondaries.iter().map(|key| key.to_vec()).collect(),
+            min_commit_ts: 0,
+            lock_ttl: 0,
+            start_time: 0,
+        }
+    };
+    // "5" is not prewritten
+    let req = prewrite(&[b"3", b"4"], b"3", &[b"4", b"5"], ts0);
+    t.call_txn(req).await.unwrap();
+    // every key is prewritten, so the transaction is committed
+    let req = prewrite(&[b"6", b"7"], b"6", &[b"7"], ts1);
+    t.call_txn(req).await.unwrap();
+    // the primary key "9" is not prewritten
+    let req = prewrite(&[b"8"], b"9", &[], ts2);
+    t.call_txn(req).await.unwrap();
+
+    // the collector rebuilds the outcomes from the locks of all keys
+    time::sleep(Duration::from_secs(3)).await;
+    let stats = t.call_txn(msg::LockGcStatsRequest {}).await;
+    assert_eq!(
+        stats,
+        msg::LockGcStats {
+            found: 5,
+            cleaned: 5
+        }
+    );
+    let check_locks = |keys: &[&[u8]], start_ts| msg::CheckLocksRequest {
+        region,
+        keys: keys.iter().map(|key| key.to_vec()).collect(),
+        start_ts,
+    };
+    let rsp = t
+        .call_txn(check_locks(&[b"3", b"4", b"5"], ts0))
+        .await
+        .unwrap();
+    assert!(rsp
+        .iter()
+        .all(|status| matches!(status, Ok(msg::LockStatus::Missing))));
+    let rsp = t.call_txn(check_locks(&[b"6", b"7"], ts1)).await.unwrap();
+    assert!(matches!(
+        rsp[..],
+        [Ok(msg::LockStatus::Committed { commit_ts: a }), Ok(msg::LockStatus::Committed { commit_ts: b })]
+            if a == b
+    ));
+    let rsp = t.call_txn(check_locks(&[b"8"], ts2)).await.unwrap();
+    assert!(matches!(rsp[..], [Ok(msg::LockStatus::Missing)]));
+
+    // the rolled back keys can no longer be prewritten
+    let req = prewrite(&[b"5"], b"3", &[], ts0);
+    assert!(t.call_txn(req).await.unwrap()[0].is_err());
+    let req = prewrite(&[b"9"], b"9", &[b"8"], ts2);
+    assert!(t.call_txn(req).await.unwrap()[0].is_err());
+}
+
+#[madsim::test]
+async fn test_lock_gc_cross_shard() {
+    let t = Tester::with_shards(1, 2).await;
+    let region0 = t.call_txn_node(0, msg::RegionsRequest {}).await.regions[0].epoch();
+    let region1 = t.call_txn_node(1, msg::RegionsRequest {}).await.regions[0].epoch();
+    let client = t.client(0);
+    let ts0 = client.get_timestamp().await.unwrap();
+    let ts1 = client.get_timestamp().await.unwrap();
+    let ts2 = client.get_timestamp().await.unwrap();
+    let ts3 = client.get_timestamp().await.unwrap();
+
+    // the locks left by crashed clients, whose primary keys are on the other node
+    let prewrite = |region, key: &[u8], primary: &[u8], start_ts| msg::PrewriteRequest {
+        region,
+        start_ts,
+        key: key.to_vec(),
+        value: b"10".to_vec(),
+        primary_key: primary.to_vec(),
+        lock_ttl: 0,
+        start_time: 1,
+    };
+    for (j, region, key, primary, start_ts) in [
+        (0, region0, b"1", b"1", ts0),
+        (1, region1, b"3", b"1", ts0),
+        (1, region1, b"4", b"4", ts1),
+        (0, region0, b"0", b"4", ts1),
+    ] {
+        (t.call_txn_node(j, prewrite(region, key, primary, start_ts)))
+            .await
+            .unwrap();
+    }
+    (t.call_txn_node(
+        0,
+        msg::CommitRequest {
+            region: region0,
+            is_primary: true,
+            key: b"1".to_vec(),
+            start_ts: ts0,
+            commit_ts: ts3,
+        },
+    ))
+    .await
+    .unwrap();
+    // an async-commit transaction, whose secondary key is on the other node
+    let prewrite_async =
+        |region, key: &[u8], secondaries: Vec<Vec<u8>>| msg::BatchPrewriteRequest {
+            region,
+            start_ts: ts2,
+            mutations: vec![msg::Mutation {
+                key: key.to_vec(),
+                value: Some(b"10".to_vec()),
+            }],
+            primary_key: b"5".to_vec(),
+            async_commit: true,
+            secondaries,
+            min_commit_ts: 0,
+            lock_ttl: 0,
+            start_time: 0,
+        };
+    let req = prewrite_async(region1, b"5", vec![b"11".to_vec()]);
+    t.call_txn_node(1, req).await.unwrap();
+    let req = prewrite_async(region0, b"11", vec![]);
+    t.call_txn_node(0, req).await.unwrap();
+
+    // the locks waiting for the other node are counted once
+    t.clog_txn_to_txn(0, 1, true);
+    time::sleep(Duration::from_secs(40)).await;
+    let stats = t.call_txn_node(0, msg::LockGcStatsRequest {}).await;
+    assert_eq!(stats.found, 2);
+    let stats = t.call_txn_node(1, msg::LockGcStatsRequest {}).await;
+    assert_eq!(stats.found, 3);
+
+    // the collectors check the transactions on the nodes owning their keys
+    t.clog_txn_to_txn(0, 1, false);
+    time::sleep(Duration::from_secs(40)).await;
+    let check_locks = |region, keys: &[&[u8]], start_ts| msg::CheckLocksRequest {
+        region,
+        keys: keys.iter().map(|key| key.to_vec()).collect(),
+        start_ts,
+    };
+    let rsp = t.call_txn_node(1, check_locks(region1, &[b"3"], ts0));
+    assert!(matches!(
+        rsp.await.unwrap()[..],
+        [Ok(msg::LockStatus::Committed { commit_ts })] if commit_ts == ts3
+    ));
+    let rsp = t.call_txn_node(0, check_locks(region0, &[b"0"], ts1));
+    assert!(matches!(
+        rsp.await.unwrap()[..],
+        [Ok(msg::LockStatus::Missing)]
+    ));
+    let rsp = t.call_txn_node(1, check_locks(region1, &[b"5"], ts2));
+    let [Ok(msg::LockStatus::Committed { commit_ts })] = rsp.await.unwrap()[..] else {
+        panic!("primary key not committed");
+    };
+    let rsp = t.call_txn_node(0, check_locks(region0, &[b"11"], ts2));
+    assert!(matches!(
+        rsp.await.unwrap()[..],
+        [Ok(msg::LockStatus::Committed { commit_ts: ts })] if ts == commit_ts
+    ));
 }
 
 #[madsim::test]
@@ -463,9 +1388,9 @@ async fn test_commit_primary_success() {
 
     let mut client1 = t.client(1);
     client1.begin().await;
-    assert_eq!(client1.get(b"3").await.unwrap(), b"30");
-    assert_eq!(client1.get(b"4").await.unwrap(), b"40");
-    assert_eq!(client1.get(b"5").await.unwrap(), b"50");
+    assert_eq!(client1.get(b"3").await.unwrap(), Some(b"30".to_vec()));
+    assert_eq!(client1.get(b"4").await.unwrap(), Some(b"40".to_vec()));
+    assert_eq!(client1.get(b"5").await.unwrap(), Some(b"50".to_vec()));
 }
 
 #[madsim::test]
@@ -483,9 +1408,9 @@ async fn test_commit_primary_success_without_response() {
 
     let mut client1 = t.client(1);
     client1.begin().await;
-    assert_eq!(client1.get(b"3").await.unwrap(), b"30");
-    assert_eq!(client1.get(b"4").await.unwrap(), b"40");
-    assert_eq!(client1.get(b"5").await.unwrap(), b"50");
+    assert_eq!(client1.get(b"3").await.unwrap(), Some(b"30".to_vec()));
+    assert_eq!(client1.get(b"4").await.unwrap(), Some(b"40".to_vec()));
+    assert_eq!(client1.get(b"5").await.unwrap(), Some(b"50".to_vec()));
 }
 
 #[madsim::test]
@@ -504,7 +1429,1014 @@ async fn test_commit_primary_fail() {
 
     let mut client1 = t.client(1);
     client1.begin().await;
-    assert_eq!(client1.get(b"3").await.unwrap(), b"");
-    assert_eq!(client1.get(b"4").await.unwrap(), b"");
-    assert_eq!(client1.get(b"5").await.unwrap(), b"");
+    assert_eq!(client1.get(b"3").await.unwrap(), None);
+    assert_eq!(client1.get(b"4").await.unwrap(), None);
+    assert_eq!(client1.get(b"5").await.unwrap(), None);
+}
+
+#[madsim::test]
+async fn test_commit_after_rollback() {
+    let t = Tester::new(3).await;
+
+    let mut client0 = t.client(0);
+    client0.set_lock_ttl(Duration::from_millis(100));
+    client0.begin().await;
+    let mut client1 = t.client(1);
+    client1.begin().await;
+
+    client0.set(b"3", b"30").await;
+    client0.set(b"4", b"40").await;
+    t.drop_commit_secondary_request();
+    t.drop_commit_primary_request();
+    let read = async {
+        // the client stops extending its locks, so a reader rolls the
+        // transaction back once they expire
+        time::sleep(Duration::from_millis(50)).await;
+        t.disable_client(0);
+        let value = client1.get(b"4").await.unwrap();
+        // the primary commit gets through on a retry
+        t.enable_client(0);
+        t.reset_drop();
+        value
+    };
+    let (committed, value) = futures::join!(client0.commit(), read);
+    assert!(!committed.unwrap());
+    assert_eq!(value, None);
+
+    let mut client2 = t.client(2);
+    client2.begin().await;
+    assert_eq!(client2.get(b"3").await.unwrap(), None);
+    assert_eq!(client2.get(b"4").await.unwrap(), None);
+}
+
+#[madsim::test]
+async fn test_lock_ttl() {
+    let t = Tester::new(3).await;
+
+    let mut client0 = t.client(0);
+    // the locks outlive their TTL while the client keeps committing
+    client0.set_lock_ttl(Duration::from_millis(100));
+    client0.begin().await;
+    let mut client1 = t.client(1);
+    client1.begin().await;
+
+    client0.set(b"3", b"30").await;
+    client0.set(b"4", b"40").await;
+    t.drop_commit_secondary_request();
+    t.drop_commit_primary_request();
+    let read = async {
+        // a reader waits for the transaction instead of rolling it back
+        time::sleep(Duration::from_millis(50)).await;
+        client1.get(b"4").await.unwrap()
+    };
+    let recover = async {
+        time::sleep(Duration::from_millis(250)).await;
+        t.reset_drop();
+    };
+    let (committed, value, _) = futures::join!(client0.commit(), read, recover);
+    assert!(committed.unwrap());
+    assert_eq!(value, None);
+
+    let mut client2 = t.client(2);
+    client2.begin().await;
+    assert_eq!(client2.get(b"3").await.unwrap(), Some(b"30".to_vec()));
+    assert_eq!(client2.get(b"4").await.unwrap(), Some(b"40".to_vec()));
+}
+
+#[madsim::test]
+async fn test_commit_and_rollback_errors() {
+    let t = Tester::new(1).await;
+    let region = t.call_txn(msg::RegionsRequest {}).await.regions[0].epoch();
+    let client = t.client(0);
+    let ts0 = client.get_timestamp().await.unwrap();
+    let ts1 = client.get_timestamp().await.unwrap();
+    let ts2 = client.get_timestamp().await.unwrap();
+
+    let prewrite = |key: &[u8], start_ts| msg::PrewriteRequest {
+        region,
+        start_ts,
+        key: key.to_vec(),
+        value: b"10".to_vec(),
+        primary_key: key.to_vec(),
+        lock_ttl: 0,
+        start_time: 0,
+    };
+    let commit = |key: &[u8], start_ts, commit_ts| msg::CommitRequest {
+        region,
+        is_primary: true,
+        key: key.to_vec(),
+        start_ts,
+        commit_ts,
+    };
+    let rollback = |key: &[u8], start_ts| msg::RollbackRequest {
+        region,
+        key: key.to_vec(),
+        start_ts,
+    };
+
+    t.call_txn(prewrite(b"1", ts1)).await.unwrap();
+    let rsp = t.call_txn(commit(b"1", ts0, ts2)).await;
+    assert!(matches!(rsp, Err(msg::CommitError::TxnLockMismatch { ts }) if ts == ts1));
+    let rsp = t.call_txn(commit(b"2", ts1, ts2)).await;
+    assert!(matches!(rsp, Err(msg::CommitError::LockNotFound)));
+
+    t.call_txn(commit(b"1", ts1, ts2)).await.unwrap();
+    // a retried commit succeeds again
+    t.call_txn(commit(b"1", ts1, ts2)).await.unwrap();
+    let rsp = t.call_txn(rollback(b"1", ts1)).await;
+    assert!(
+        matches!(rsp, Err(msg::RollbackError::AlreadyCommitted { commit_ts }) if commit_ts == ts2)
+    );
+
+    // a rollback arriving before the prewrite leaves a record behind
+    t.call_txn(rollback(b"2", ts2)).await.unwrap();
+    let rsp = t.call_txn(prewrite(b"2", ts2)).await;
+    assert!(matches!(rsp, Err(msg::PrewriteError::AlreadyRolledBack)));
+    let rsp = t.call_txn(commit(b"2", ts2, ts2 + 1)).await;
+    assert!(matches!(rsp, Err(msg::CommitError::AlreadyRolledBack)));
+}
+
+#[madsim::test]
+async fn test_check_txn_status() {
+    let t = Tester::new(1).await;
+    let region = t.call_txn(msg::RegionsRequest {}).await.regions[0].epoch();
+    let client = t.client(0);
+    let ts0 = client.get_timestamp().await.unwrap();
+    let ts1 = client.get_timestamp().await.unwrap();
+
+    let prewrite = |key: &[u8], start_ts| msg::PrewriteRequest {
+        region,
+        start_ts,
+        key: key.to_vec(),
+        value: b"10".to_vec(),
+        primary_key: key.to_vec(),
+        lock_ttl: 1000,
+        start_time: 0,
+    };
+    let check = |key: &[u8], current_time, rollback_if_not_exist| msg::CheckTxnStatusRequest {
+        region,
+        primary_key: key.to_vec(),
+        lock_ts: ts0,
+        current_time,
+        rollback_if_not_exist,
+    };
+
+    // a lock alive keeps the transaction going
+    t.call_txn(prewrite(b"1", ts0)).await.unwrap();
+    let rsp = t.call_txn(check(b"1", 999, true)).await;
+    assert_eq!(rsp.unwrap(), msg::TxnStatus::Locked { ttl: 1000 });
+    t.call_txn(msg::CommitRequest {
+        region,
+        is_primary: true,
+        key: b"1".to_vec(),
+        start_ts: ts0,
+        commit_ts: ts1,
+    })
+    .await
+    .unwrap();
+    let rsp = t.call_txn(check(b"1", 999, true)).await;
+    assert_eq!(rsp.unwrap(), msg::TxnStatus::Committed(ts1));
+
+    // an expired lock is rolled back
+    t.call_txn(prewrite(b"2", ts0)).await.unwrap();
+    let rsp = t.call_txn(check(b"2", 1000, false)).await;
+    assert_eq!(rsp.unwrap(), msg::TxnStatus::RolledBack);
+    let rsp = t.call_txn(check(b"2", 0, false)).await;
+    assert_eq!(rsp.unwrap(), msg::TxnStatus::RolledBack);
+
+    // a missing lock is rolled back on request, which fails a later prewrite
+    let rsp = t.call_txn(check(b"3", 0, false)).await;
+    assert_eq!(rsp.unwrap(), msg::TxnStatus::Uncommitted);
+    let rsp = t.call_txn(check(b"3", 0, true)).await;
+    assert_eq!(rsp.unwrap(), msg::TxnStatus::RolledBack);
+    let rsp = t.call_txn(prewrite(b"3", ts0)).await;
+    assert!(matches!(rsp, Err(msg::PrewriteError::AlreadyRolledBack)));
+
+    // a lock whose TTL runs past the end of time never expires
+    let req = msg::PrewriteRequest {
+        lock_ttl: u64::MAX,
+        start_time: 1,
+        ..prewrite(b"4", ts0)
+    };
+    t.call_txn(req).await.unwrap();
+    let rsp = t.call_txn(check(b"4", 1000, true)).await;
+    assert_eq!(rsp.unwrap(), msg::TxnStatus::Locked { ttl: u64::MAX });
+}
+
+#[madsim::test]
+async fn test_rollback_after_prewrite_fail() {
+    let t = Tester::new(3).await;
+    let region = t.call_txn(msg::RegionsRequest {}).await.regions[0].epoch();
+
+    let mut client0 = t.client(0);
+    client0.begin().await;
+    let mut client1 = t.client(1);
+    client1.begin().await;
+    client1.set(b"2", b"20").await;
+    assert!(client1.commit().await.unwrap());
+
+    // the primary key gets locked, but the secondary conflicts
+    client0.set(b"1", b"10").await;
+    client0.set(b"2", b"21").await;
+    let start_ts = client0.start_ts().await;
+    assert!(!client0.commit().await.unwrap());
+
+    let rsp = (t.call_txn(msg::CheckLocksRequest {
+        region,
+        keys: vec![b"1".to_vec()],
+        start_ts,
+    }))
+    .await;
+    assert!(matches!(rsp.unwrap()[..], [Ok(msg::LockStatus::Missing)]));
+    let rsp = (t.call_txn(msg::PrewriteRequest {
+        region,
+        start_ts,
+        key: b"1".to_vec(),
+        value: b"10".to_vec(),
+        primary_key: b"1".to_vec(),
+        lock_ttl: 0,
+        start_time: 0,
+    }))
+    .await;
+    assert!(matches!(rsp, Err(msg::PrewriteError::AlreadyRolledBack)));
+
+    // an explicit rollback discards the buffered writes
+    let mut client2 = t.client(2);
+    client2.begin().await;
+    client2.set(b"1", b"12").await;
+    client2.rollback().await.unwrap();
+    client2.begin().await;
+    assert_eq!(client2.get(b"1").await.unwrap(), None);
+    assert_eq!(client2.get(b"2").await.unwrap(), Some(b"20".to_vec()));
+}
+
+#[madsim::test]
+async fn test_cross_shard_transaction() {
+    let t = Tester::with_shards(4, 3).await;
+
+    let mut client0 = t.client(0);
+    client0.begin().await;
+    client0.set(b"1", b"10").await;
+    client0.set(b"2", b"20").await;
+    client0.set(b"3", b"30").await;
+    client0.set(b"4", b"40").await;
+    assert!(client0.commit().await.unwrap());
+
+    let mut client1 = t.client(1);
+    client1.begin().await;
+    let mut client2 = t.client(2);
+    client2.begin().await;
+
+    assert_eq!(client1.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    assert_eq!(client1.get(b"4").await.unwrap(), Some(b"40".to_vec()));
+    client1.set(b"1", b"11").await;
+    client1.set(b"4", b"41").await;
+    client2.set(b"2", b"22").await;
+    client2.set(b"4", b"42").await;
+    assert!(client1.commit().await.unwrap());
+    assert!(!client2.commit().await.unwrap());
+
+    let mut client3 = t.client(3);
+    client3.begin().await;
+    assert_eq!(client3.get(b"1").await.unwrap(), Some(b"11".to_vec()));
+    assert_eq!(client3.get(b"2").await.unwrap(), Some(b"20".to_vec()));
+    assert_eq!(client3.get(b"3").await.unwrap(), Some(b"30".to_vec()));
+    assert_eq!(client3.get(b"4").await.unwrap(), Some(b"41".to_vec()));
+}
+
+#[madsim::test]
+async fn test_cross_shard_commit_primary_success() {
+    let t = Tester::with_shards(2, 3).await;
+
+    let mut client0 = t.client(0);
+    client0.begin().await;
+    client0.set(b"1", b"10").await;
+    client0.set(b"2", b"20").await;
+    client0.set(b"3", b"30").await;
+    t.drop_commit_secondary_request();
+    assert!(client0.commit().await.unwrap());
+    t.reset_drop();
+
+    let mut client1 = t.client(1);
+    client1.begin().await;
+    assert_eq!(client1.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    assert_eq!(client1.get(b"2").await.unwrap(), Some(b"20".to_vec()));
+    assert_eq!(client1.get(b"3").await.unwrap(), Some(b"30".to_vec()));
+}
+
+#[madsim::test]
+async fn test_cross_shard_commit_primary_fail() {
+    let t = Tester::with_shards(2, 3).await;
+
+    let mut client0 = t.client(0);
+    client0.begin().await;
+    client0.set(b"1", b"10").await;
+    client0.set(b"2", b"20").await;
+    client0.set(b"3", b"30").await;
+    t.drop_commit_secondary_request();
+    t.drop_commit_primary_request();
+    assert!(client0.commit().await.is_err());
+    t.reset_drop();
+
+    let mut client1 = t.client(1);
+    client1.begin().await;
+    assert_eq!(client1.get(b"2").await.unwrap(), None);
+    assert_eq!(client1.get(b"3").await.unwrap(), None);
+    assert_eq!(client1.get(b"1").await.unwrap(), None);
+}
+
+#[madsim::test]
+async fn test_begin_while_committing_in_background() {
+    let t = Tester::with_shards(1, 2).await;
+
+    // the secondary key "3" is left locked while committing in the background
+    let mut client0 = t.client(0);
+    client0.begin().await;
+    client0.set(b"1", b"10").await;
+    client0.set(b"3", b"30").await;
+    t.drop_commit_secondary_request();
+    assert!(client0.commit().await.unwrap());
+
+    // the next transaction does not wait for it until running into the lock
+    assert!(time::timeout(Duration::from_millis(300), client0.begin())
+        .await
+        .is_ok());
+    assert_eq!(client0.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    t.reset_drop();
+    assert_eq!(client0.get(b"3").await.unwrap(), Some(b"30".to_vec()));
+}
+
+#[madsim::test]
+async fn test_async_commit() {
+    let t = Tester::with_shards(4, 3).await;
+
+    let mut client0 = t.client(0);
+    client0.begin().await;
+    client0.set(b"1", b"10").await;
+    client0.set(b"2", b"20").await;
+    client0.set(b"3", b"30").await;
+    assert!(client0.commit().await.unwrap());
+
+    // client 1 reads before client 2 prewrites
+    let mut client1 = t.client(1);
+    client1.begin().await;
+    assert_eq!(client1.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+
+    // client 2 is committed without any commit request getting through
+    let mut client2 = t.client(2);
+    client2.set_async_commit(true);
+    client2.begin().await;
+    client2.set(b"1", b"11").await;
+    client2.set(b"2", b"21").await;
+    client2.set(b"3", b"31").await;
+    t.drop_commit_primary_request();
+    t.drop_commit_secondary_request();
+    assert!(client2.commit().await.unwrap());
+
+    // the transaction is committed above the reads served before
+    assert_eq!(client1.get(b"2").await.unwrap(), Some(b"20".to_vec()));
+    assert_eq!(client1.get(b"3").await.unwrap(), Some(b"30".to_vec()));
+
+    // the outcome is rebuilt from the locks
+    let mut client3 = t.client(3);
+    client3.begin().await;
+    assert_eq!(client3.get(b"3").await.unwrap(), Some(b"31".to_vec()));
+    assert_eq!(client3.get(b"1").await.unwrap(), Some(b"11".to_vec()));
+    assert_eq!(client3.get(b"2").await.unwrap(), Some(b"21".to_vec()));
+    t.reset_drop();
+}
+
+#[madsim::test]
+async fn test_async_commit_above_issued_timestamps() {
+    let t = Tester::with_shards(2, 2).await;
+
+    let mut client0 = t.client(0);
+    client0.set_async_commit(true);
+    client0.begin().await;
+    client0.set(b"1", b"10").await;
+    client0.set(b"3", b"30").await;
+
+    // client 1 begins after client 0 but before it commits
+    let mut client1 = t.client(1);
+    client1.begin().await;
+    assert!(client0.commit().await.unwrap());
+
+    // the transaction is committed above the start timestamp of client 1
+    assert_eq!(client1.get(b"1").await.unwrap(), None);
+    assert_eq!(client1.get(b"3").await.unwrap(), None);
+}
+
+#[madsim::test]
+async fn test_one_phase_commit() {
+    let t = Tester::with_shards(4, 2).await;
+
+    let mut client0 = t.client(0);
+    client0.set_one_phase_commit(true);
+    client0.begin().await;
+    let mut client1 = t.client(1);
+    client1.set_one_phase_commit(true);
+    client1.begin().await;
+
+    // no commit request gets through
+    t.drop_commit_primary_request();
+    t.drop_commit_secondary_request();
+    client0.set(b"1", b"10").await;
+    client0.set(b"12", b"120").await;
+    assert!(client0.commit().await.unwrap());
+
+    // conflicts are checked like prewrites
+    client1.set(b"12", b"121").await;
+    assert!(!client1.commit().await.unwrap());
+
+    // keys on two storage nodes take two phases
+    let mut client2 = t.client(2);
+    client2.set_one_phase_commit(true);
+    client2.begin().await;
+    client2.set(b"1", b"11").await;
+    client2.set(b"2", b"21").await;
+    assert!(client2.commit().await.is_err());
+    t.reset_drop();
+
+    let mut client3 = t.client(3);
+    client3.begin().await;
+    assert_eq!(client3.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    assert_eq!(client3.get(b"12").await.unwrap(), Some(b"120".to_vec()));
+    assert_eq!(client3.get(b"2").await.unwrap(), None);
+}
+
+#[madsim::test]
+async fn test_one_phase_commit_with_timestamp_batch() {
+    let t = Tester::new(2).await;
+    let mut client0 = t.client(0);
+    client0.set_one_phase_commit(true);
+    client0.set_timestamp_batch(100);
+    client0.begin().await;
+    client0.set(b"1", b"10").await;
+
+    // the storage node serves a read above the cached timestamps of client 0
+    let mut client1 = t.client(1);
+    client1.begin().await;
+    assert_eq!(client1.get(b"1").await.unwrap(), None);
+
+    assert!(client0.commit().await.unwrap());
+    assert_eq!(client1.get(b"1").await.unwrap(), None);
+
+    // client 0 sees its own commit
+    client0.begin().await;
+    assert_eq!(client0.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+}
+
+#[madsim::test]
+async fn test_delete() {
+    let t = Tester::with_shards(4, 2).await;
+
+    let mut client0 = t.client(0);
+    client0.begin().await;
+    client0.set(b"1", b"").await;
+    client0.set(b"12", b"120").await;
+    client0.set(b"2", b"20").await;
+    assert!(client0.commit().await.unwrap());
+
+    let mut client1 = t.client(1);
+    client1.begin().await;
+    assert_eq!(client1.get(b"12").await.unwrap(), Some(b"120".to_vec()));
+    assert_eq!(client1.get(b"2").await.unwrap(), Some(b"20".to_vec()));
+    // the secondaries are left locked, and a reader resolves them
+    t.drop_commit_secondary_request();
+    client1.delete(b"2").await;
+    client1.delete(b"12").await;
+    assert!(client1.commit().await.unwrap());
+    t.reset_drop();
+
+    // keys on one storage node are deleted in one phase
+    let mut client2 = t.client(2);
+    client2.set_one_phase_commit(true);
+    client2.begin().await;
+    client2.delete(b"1").await;
+    client2.set(b"10", b"").await;
+    assert!(client2.commit().await.unwrap());
+
+    let mut client3 = t.client(3);
+    client3.begin().await;
+    assert_eq!(client3.get(b"1").await.unwrap(), None);
+    assert_eq!(client3.get(b"10").await.unwrap(), Some(vec![]));
+    assert_eq!(client3.get(b"12").await.unwrap(), None);
+    assert_eq!(client3.get(b"2").await.unwrap(), None);
+}
+
+#[madsim::test]
+async fn test_concurrent_transactions() {
+    let t = Tester::new(2).await;
+
+    // the transactions share one client
+    let client0 = t.client(0);
+    let client = client0.client.lock().clone();
+    let run = async move {
+        let (mut txn0, mut txn1) = futures::try_join!(client.begin(), client.begin()).unwrap();
+        txn0.set(b"1", b"10").await;
+        txn1.set(b"2", b"20").await;
+
+        let mut txn2 = client.begin().await.unwrap();
+        txn2.set(b"1", b"11").await;
+        txn2.rollback().await.unwrap();
+        let mut txn3 = client.begin().await.unwrap();
+        txn3.set(b"2", b"21").await;
+        drop(txn3);
+
+        let tasks = [txn0, txn1].map(|txn| task::spawn(txn.commit()));
+        for task in tasks {
+            assert!(task.await.unwrap().unwrap());
+        }
+    };
+    client0.node.spawn(run).await.unwrap();
+
+    let mut client1 = t.client(1);
+    client1.begin().await;
+    assert_eq!(client1.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    assert_eq!(client1.get(b"2").await.unwrap(), Some(b"20".to_vec()));
+}
+
+#[madsim::test]
+async fn test_batch_get() {
+    let t = Tester::with_shards(3, 2).await;
+
+    let mut client0 = t.client(0);
+    client0.begin().await;
+    client0.set(b"1", b"10").await;
+    client0.set(b"12", b"120").await;
+    client0.set(b"2", b"20").await;
+    // the secondaries are left locked, and only they are resolved
+    t.drop_commit_secondary_request();
+    assert!(client0.commit().await.unwrap());
+    t.reset_drop();
+
+    let mut client1 = t.client(1);
+    client1.begin().await;
+    let values = client1.batch_get(&[b"2", b"0", b"1", b"12", b"2"]).await;
+    assert_eq!(
+        values.unwrap(),
+        [
+            Some(b"20".to_vec()),
+            None,
+            Some(b"10".to_vec()),
+            Some(b"120".to_vec()),
+            Some(b"20".to_vec()),
+        ]
+    );
+    client1.set(b"0", b"01").await;
+    client1.delete(b"12").await;
+    assert!(client1.commit().await.unwrap());
+
+    let mut client2 = t.client(2);
+    client2.begin().await;
+    let values = client2.batch_get(&[b"0", b"12"]).await;
+    assert_eq!(values.unwrap(), [Some(b"01".to_vec()), None]);
+}
+
+#[madsim::test]
+async fn test_scan() {
+    let t = Tester::with_shards(4, 3).await;
+
+    let mut client0 = t.client(0);
+    client0.begin().await;
+    for key in [b"0", b"1", b"2", b"3", b"4"] {
+        client0.set(key, b"0").await;
+    }
+    assert!(client0.commit().await.unwrap());
+
+    // the secondaries are left locked, and the scan resolves them
+    let mut client1 = t.client(1);
+    client1.begin().await;
+    let pairs = |keys: &[&[u8]], value: &[u8]| -> Vec<msg::KvPair> {
+        (keys.iter())
+            .map(|key| (key.to_vec(), value.to_vec()))
+            .collect()
+    };
+    assert_eq!(
+        client1.scan(b"", b"", 10).await.unwrap(),
+        pairs(&[b"0", b"1", b"2", b"3", b"4"], b"0")
+    );
+    client1.set(b"1", b"1").await;
+    client1.delete(b"2").await;
+    client1.set(b"25", b"1").await;
+    client1.set(b"3", b"1").await;
+    let mut client3 = t.client(3);
+    client3.begin().await;
+    t.drop_commit_secondary_request();
+    assert!(client1.commit().await.unwrap());
+    t.reset_drop();
+
+    let mut client2 = t.client(2);
+    client2.begin().await;
+    assert_eq!(
+        client2.scan(b"1", b"3", 10).await.unwrap(),
+        pairs(&[b"1", b"25"], b"1")
+    );
+    assert_eq!(
+        client2.scan(b"", b"", 3).await.unwrap(),
+        [pairs(&[b"0"], b"0"), pairs(&[b"1", b"25"], b"1")].concat()
+    );
+    assert_eq!(
+        client2.scan_reverse(b"1", b"", 3).await.unwrap(),
+        [pairs(&[b"4"], b"0"), pairs(&[b"3", b"25"], b"1")].concat()
+    );
+    assert_eq!(
+        client2.scan_reverse(b"", b"25", 10).await.unwrap(),
+        [pairs(&[b"1"], b"1"), pairs(&[b"0"], b"0")].concat()
+    );
+    // a transaction started before the second one committed
+    assert_eq!(
+        client3.scan_reverse(b"2", b"4", 10).await.unwrap(),
+        pairs(&[b"3", b"2"], b"0")
+    );
+}
+
+#[madsim::test]
+async fn test_region_split_and_merge() {
+    let t = Tester::with_shards(4, 2).await;
+
+    let mut client0 = t.client(0);
+    client0.begin().await;
+    client0.set(b"1", b"10").await;
+    client0.set(b"3", b"30").await;
+    client0.set(b"5", b"50").await;
+    assert!(client0.commit().await.unwrap());
+
+    // leave the lock of "5" behind
+    let mut client1 = t.client(1);
+    client1.begin().await;
+    client1.set(b"2", b"21").await;
+    client1.set(b"5", b"51").await;
+    t.drop_commit_secondary_request();
+    assert!(client1.commit().await.unwrap());
+    t.reset_drop();
+
+    // client 2 learns the routes before the regions change
+    let mut client2 = t.client(2);
+    client2.begin().await;
+    assert_eq!(client2.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    assert_eq!(client2.get(b"3").await.unwrap(), Some(b"30".to_vec()));
+
+    // move the keys from "4" on to the first storage node
+    let admin = t.client(3);
+    admin
+        .split_region(b"4", Some(t.txn_addrs[0]))
+        .await
+        .unwrap();
+    admin.split_region(b"3", None).await.unwrap();
+
+    assert_eq!(client2.get(b"3").await.unwrap(), Some(b"30".to_vec()));
+    assert_eq!(client2.get(b"5").await.unwrap(), Some(b"51".to_vec()));
+    client2.set(b"3", b"32").await;
+    client2.set(b"4", b"42").await;
+    assert!(client2.commit().await.unwrap());
+
+    // move the keys from "4" on back to the second storage node
+    admin.merge_region(b"4").await.unwrap();
+    admin.merge_region(b"3").await.unwrap();
+
+    let mut client3 = t.client(3);
+    client3.begin().await;
+    assert_eq!(client3.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    assert_eq!(client3.get(b"2").await.unwrap(), Some(b"21".to_vec()));
+    assert_eq!(client3.get(b"3").await.unwrap(), Some(b"32".to_vec()));
+    assert_eq!(client3.get(b"4").await.unwrap(), Some(b"42".to_vec()));
+    assert_eq!(client3.get(b"5").await.unwrap(), Some(b"51".to_vec()));
+
+    // the regions survive restarts
+    t.restart_txn();
+    time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(client3.get(b"4").await.unwrap(), Some(b"42".to_vec()));
+}
+
+#[madsim::test]
+async fn test_merge_into_changed_region() {
+    let t = Tester::with_shards(2, 2).await;
+
+    let mut client0 = t.client(0);
+    client0.begin().await;
+    client0.set(b"1", b"10").await;
+    client0.set(b"3", b"30").await;
+    assert!(client0.commit().await.unwrap());
+
+    // the second storage node can not install regions on the first one for now
+    let admin = t.client(1);
+    admin.split_region(b"1", None).await.unwrap();
+    let dropping = Arc::new(AtomicBool::new(true));
+    let txn = Handle::current().get_node(txn_name(2, 1)).unwrap();
+    madsim::net::NetSim::current().hook_rpc_req(txn.id(), {
+        let dropping = dropping.clone();
+        move |_: &msg::InstallRegionRequest| !dropping.load(Ordering::Relaxed)
+    });
+    admin.merge_region(b"3").await.unwrap();
+
+    // the region to merge into is merged away in the meantime
+    admin.merge_region(b"1").await.unwrap();
+    dropping.store(false, Ordering::Relaxed);
+    time::sleep(Duration::from_secs(3)).await;
+
+    // the second storage node takes its region back
+    client0.begin().await;
+    assert_eq!(client0.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    assert_eq!(client0.get(b"3").await.unwrap(), Some(b"30".to_vec()));
+    client0.set(b"3", b"31").await;
+    assert!(client0.commit().await.unwrap());
+}
+
+#[madsim::test]
+async fn test_async_commit_client_dies_while_prewriting() {
+    let mut t = Tester::with_shards(3, 2).await;
+
+    // client 0 dies once it has prewritten the primary key "1" but not "3"
+    let mut client0 = t.client(0);
+    client0.set_async_commit(true);
+    client0.set_lock_ttl(Duration::from_millis(500));
+    client0.begin().await;
+    // learn the routes first
+    client0.get(b"1").await.unwrap();
+    client0.get(b"3").await.unwrap();
+    client0.set(b"1", b"10").await;
+    client0.set(b"3", b"30").await;
+    t.clog_client_to_txn(0, 1, true);
+    assert!(time::timeout(Duration::from_millis(300), client0.commit())
+        .await
+        .is_err());
+    t.restart_client(0).await;
+    t.clog_client_to_txn(0, 1, false);
+
+    // client 1 dies once it has prewritten "3" but not the primary key "1"
+    let mut client1 = t.client(1);
+    client1.set_async_commit(true);
+    client1.set_lock_ttl(Duration::from_millis(500));
+    client1.begin().await;
+    // learn the routes first
+    client1.get(b"1").await.unwrap();
+    client1.get(b"3").await.unwrap();
+    client1.set(b"1", b"11").await;
+    client1.set(b"3", b"31").await;
+    t.clog_client_to_txn(1, 0, true);
+    assert!(time::timeout(Duration::from_millis(300), client1.commit())
+        .await
+        .is_err());
+    t.restart_client(1).await;
+    t.clog_client_to_txn(1, 0, false);
+
+    // a reader rolls both back once their locks expire
+    let mut client2 = t.client(2);
+    client2.begin().await;
+    assert_eq!(client2.get(b"3").await.unwrap(), None);
+    assert_eq!(client2.get(b"1").await.unwrap(), None);
+    client2.set(b"1", b"12").await;
+    client2.set(b"3", b"32").await;
+    assert!(client2.commit().await.unwrap());
+}
+
+#[madsim::test]
+async fn test_async_commit_after_region_moved() {
+    let t = Tester::with_shards(3, 2).await;
+
+    let mut client0 = t.client(0);
+    client0.set_async_commit(true);
+    client0.begin().await;
+    client0.set(b"1", b"10").await;
+
+    // the first storage node serves a read above the start of client 0
+    let mut client1 = t.client(1);
+    client1.begin().await;
+    assert_eq!(client1.get(b"1").await.unwrap(), None);
+
+    // move the keys from "1" on to the second storage node
+    let admin = t.client(2);
+    admin
+        .split_region(b"1", Some(t.txn_addrs[1]))
+        .await
+        .unwrap();
+
+    // which commits above the read
+    assert!(client0.commit().await.unwrap());
+    assert_eq!(client1.get(b"1").await.unwrap(), None);
+}
+
+#[madsim::test]
+async fn test_async_commit_with_timestamp_batch() {
+    let t = Tester::new(2).await;
+    let mut client0 = t.client(0);
+    client0.set_async_commit(true);
+    client0.set_timestamp_batch(100);
+    client0.begin().await;
+    client0.set(b"1", b"10").await;
+
+    // the storage node serves a read above the cached timestamps of client 0
+    let mut client1 = t.client(1);
+    client1.begin().await;
+    assert_eq!(client1.get(b"1").await.unwrap(), None);
+
+    // client 0 sees its own commit, which is above the read
+    assert!(client0.commit().await.unwrap());
+    client0.begin().await;
+    assert_eq!(client0.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+}
+
+#[madsim::test]
+async fn test_batch_regrouped_after_region_split() {
+    let t = Tester::with_shards(3, 2).await;
+
+    // client 0 learns the routes before the regions change
+    let mut client0 = t.client(0);
+    client0.begin().await;
+    assert_eq!(client0.get(b"1").await.unwrap(), None);
+    client0.set(b"1", b"10").await;
+    client0.set(b"12", b"120").await;
+    client0.set(b"14", b"140").await;
+    client0.set(b"2", b"20").await;
+
+    // the batch of the first region is split in three, one part moving away
+    let admin = t.client(1);
+    admin
+        .split_region(b"14", Some(t.txn_addrs[1]))
+        .await
+        .unwrap();
+    admin.split_region(b"12", None).await.unwrap();
+    assert!(client0.commit().await.unwrap());
+
+    let mut client2 = t.client(2);
+    client2.begin().await;
+    assert_eq!(client2.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    assert_eq!(client2.get(b"12").await.unwrap(), Some(b"120".to_vec()));
+    assert_eq!(client2.get(b"14").await.unwrap(), Some(b"140".to_vec()));
+    assert_eq!(client2.get(b"2").await.unwrap(), Some(b"20".to_vec()));
+}
+
+#[madsim::test]
+async fn test_storage_group_leader_failover() {
+    let t = Tester::with_storage_group(5, 3).await;
+
+    let mut client0 = t.client(0);
+    client0.begin().await;
+    client0.set(b"1", b"10").await;
+    client0.set(b"2", b"20").await;
+    assert!(client0.commit().await.unwrap());
+
+    // kill the leader in the middle of a transaction
+    let mut client1 = t.client(1);
+    client1.begin().await;
+    client1.set(b"1", b"11").await;
+    client1.set(b"2", b"21").await;
+    let leader = t.txn_leader().await;
+    let commit = task::spawn(async move { client1.commit().await });
+    time::sleep(Duration::from_millis(20)).await;
+    t.kill_txn_member(leader);
+    let committed = commit.await.unwrap();
+
+    let mut client2 = t.client(2);
+    client2.begin().await;
+    let values = (
+        client2.get(b"1").await.unwrap(),
+        client2.get(b"2").await.unwrap(),
+    );
+    let old = (Some(b"10".to_vec()), Some(b"20".to_vec()));
+    let new = (Some(b"11".to_vec()), Some(b"21".to_vec()));
+    if committed.is_ok_and(|ok| ok) {
+        assert_eq!(values, new);
+    } else {
+        // the transaction is atomic either way
+        assert!(values == old || values == new);
+    }
+
+    // the old leader catches up after restarting, and the next one fails
+    t.restart_txn_member(leader);
+    let leader = t.txn_leader().await;
+    t.kill_txn_member(leader);
+
+    let mut client3 = t.client(3);
+    client3.begin().await;
+    client3.set(b"3", b"30").await;
+    assert!(client3.commit().await.unwrap());
+
+    let mut client4 = t.client(4);
+    client4.begin().await;
+    assert_eq!(client4.get(b"1").await.unwrap(), values.0);
+    assert_eq!(client4.get(b"2").await.unwrap(), values.1);
+    assert_eq!(client4.get(b"3").await.unwrap(), Some(b"30".to_vec()));
+}
+
+#[madsim::test]
+async fn test_storage_group_keeps_locks_after_failover() {
+    let t = Tester::with_storage_group(3, 3).await;
+
+    // leave the locks of the secondaries behind
+    let mut client0 = t.client(0);
+    client0.begin().await;
+    client0.set(b"3", b"30").await;
+    client0.set(b"4", b"40").await;
+    t.drop_commit_secondary_request();
+    assert!(client0.commit().await.unwrap());
+    t.reset_drop();
+
+    // a prewritten transaction whose primary is never committed
+    let mut client1 = t.client(1);
+    client1.begin().await;
+    client1.set(b"5", b"50").await;
+    client1.set(b"6", b"60").await;
+    t.drop_commit_primary_request();
+    t.drop_commit_secondary_request();
+    assert!(client1.commit().await.is_err());
+    t.reset_drop();
+
+    let leader = t.txn_leader().await;
+    t.kill_txn_member(leader);
+
+    // the new leader resolves the locks from the replicated records
+    let mut client2 = t.client(2);
+    client2.begin().await;
+    assert_eq!(client2.get(b"3").await.unwrap(), Some(b"30".to_vec()));
+    assert_eq!(client2.get(b"4").await.unwrap(), Some(b"40".to_vec()));
+    assert_eq!(client2.get(b"5").await.unwrap(), None);
+    assert_eq!(client2.get(b"6").await.unwrap(), None);
+}
+
+#[madsim::test]
+async fn test_storage_group_restart_after_reads() {
+    let t = Tester::with_storage_group(2, 3).await;
+
+    let mut client0 = t.client(0);
+    client0.begin().await;
+    client0.set(b"1", b"10").await;
+    assert!(client0.commit().await.unwrap());
+
+    // enough reads for the log to be compacted
+    let mut client1 = t.client(1);
+    for _ in 0..1200 {
+        client1.begin().await;
+        assert_eq!(client1.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+        assert!(client1.commit().await.unwrap());
+    }
+
+    t.restart_txn();
+    t.txn_leader().await;
+
+    client1.begin().await;
+    assert_eq!(client1.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    client1.set(b"1", b"11").await;
+    assert!(client1.commit().await.unwrap());
+}
+
+#[madsim::test]
+async fn test_storage_group_follower_read() {
+    let t = Tester::with_storage_group(3, 3).await;
+
+    let mut client0 = t.client(0);
+    client0.begin().await;
+    client0.set(b"1", b"10").await;
+    client0.set(b"2", b"20").await;
+    assert!(client0.commit().await.unwrap());
+
+    // the first read goes through the leader, which lets the followers
+    // serve later reads at the same timestamp
+    let mut client1 = t.client(1);
+    client1.set_follower_read(true);
+    client1.begin().await;
+    assert_eq!(client1.get(b"1").await.unwrap(), Some(b"10".to_vec()));
+    time::sleep(Duration::from_secs(1)).await;
+
+    // no leader can be elected with a single member left
+    let leader = t.txn_leader().await;
+    t.kill_txn_member(leader);
+    t.kill_txn_member((leader + 1) % 3);
+    assert_eq!(client1.get(b"2").await.unwrap(), Some(b"20".to_vec()));
+
+    // the follower has not seen anything at a later timestamp
+    let mut client2 = t.client(2);
+    client2.set_follower_read(true);
+    client2.begin().await;
+    assert!(client2.get(b"1").await.is_err());
+}
+
+#[madsim::test]
+async fn test_commit_many_keys_concurrently() {
+    let t = Tester::with_shards(2, 3).await;
+
+    let mut client0 = t.client(0);
+    client0.begin().await;
+    for shard in 1..=3 {
+        for i in 0..10 {
+            client0.set(format!("{shard}-{i}").as_bytes(), b"10").await;
+        }
+    }
+    t.drop_commit_secondary_request();
+    // the keys are prewritten at once, and the secondaries are not waited for
+    let start = time::Instant::now();
+    assert!(client0.commit().await.unwrap());
+    assert!(start.elapsed() < Duration::from_millis(500));
+    t.reset_drop();
+
+    let mut client1 = t.client(1);
+    client1.begin().await;
+    for shard in 1..=3 {
+        for i in 0..10 {
+            let key = format!("{shard}-{i}");
+            let value = client1.get(key.as_bytes()).await.unwrap();
+            assert_eq!(value, Some(b"10".to_vec()));
+        }
+    }
 }